@@ -0,0 +1,449 @@
+use crate::{
+    data::{Error, InputSource, Position, RowError, Timestamp, Transaction, TypedTransaction},
+    read::{parse_line, Failures, ReadOptions, ReadReport, TransactionUser},
+};
+use futures_core::Stream;
+use std::{
+    future::{poll_fn, Future},
+    pin::Pin,
+};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+
+/// Async counterpart of `TransactionUser`, for users having to wait on something (a
+/// database, another service...) to apply a transaction.
+pub trait AsyncTransactionUser {
+    fn use_tx(&mut self, tx: TypedTransaction) -> impl Future<Output = Result<(), Error>> + Send;
+    /// Like `TransactionUser::use_tx_at`, ignores the timestamp unless overridden
+    fn use_tx_at(
+        &mut self,
+        tx: TypedTransaction,
+        _timestamp: Option<Timestamp>,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        self.use_tx(tx)
+    }
+}
+
+/// Adapter making a `TransactionUser` (e.g. `Accounts`) an `AsyncTransactionUser`.
+/// Transactions are applied right away, which is fine for in-memory users; but a `Wal`
+/// would block the executor while syncing, so it's better run on its own thread.
+pub struct AsyncAdapter<U>(pub U);
+
+impl<U: TransactionUser + Send> AsyncTransactionUser for AsyncAdapter<U> {
+    async fn use_tx(&mut self, tx: TypedTransaction) -> Result<(), Error> {
+        self.0.use_tx(tx)
+    }
+    async fn use_tx_at(
+        &mut self,
+        tx: TypedTransaction,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), Error> {
+        self.0.use_tx_at(tx, timestamp)
+    }
+}
+
+async fn apply<U: AsyncTransactionUser>(
+    tx: Transaction,
+    source: InputSource,
+    user: &mut U,
+) -> Result<(), Error> {
+    let timestamp = tx.timestamp;
+    let tx = source
+        .check(&tx)
+        .and_then(|()| TypedTransaction::try_from(tx))?;
+    user.use_tx_at(tx, timestamp).await
+}
+
+/// The next line of `reader`, without its line break, and how many bytes it took
+async fn next_line<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+    line: &mut Vec<u8>,
+) -> Result<usize, anyhow::Error> {
+    line.clear();
+    let read = reader.read_until(b'\n', line).await?;
+    while line
+        .last()
+        .is_some_and(|&byte| byte == b'\n' || byte == b'\r')
+    {
+        line.pop();
+    }
+    Ok(read)
+}
+
+/// Splits a line into fields; it's malformed if they don't match `headers`
+fn parse_row(
+    line: &[u8],
+    headers: &csv::StringRecord,
+) -> Result<(Transaction, csv::StringRecord), (csv::StringRecord, String)> {
+    let none = csv::StringRecord::new();
+    let line = std::str::from_utf8(line).map_err(|e| (none.clone(), e.to_string()))?;
+    let record = parse_line(line).map_err(|e| (none.clone(), e.to_string()))?;
+    if record.len() != headers.len() {
+        let message = format!("found {} fields instead of {}", record.len(), headers.len());
+        return Err((none, message));
+    }
+    match record.deserialize(Some(headers)) {
+        Ok(tx) => Ok((tx, record)),
+        Err(e) => {
+            let message = match e.kind() {
+                csv::ErrorKind::Deserialize { err, .. } => err.to_string(),
+                _ => e.to_string(),
+            };
+            Err((record, message))
+        }
+    }
+}
+
+/// Async counterpart of `read_transactions`, reading CSV from an `AsyncRead`. A row is
+/// only read once the previous transaction was handled by `user`, so a slow user slows
+/// the reading down instead of having rows pile up in memory.
+///
+/// Rows are read line by line, so quoted fields can't hold newlines (transactions never
+/// need them). Otherwise failed rows are handled as by `read_transactions`, according to
+/// `options`, and returned in the same report; a header which can't be read is a malformed
+/// row too, after which every row is, since its columns are unknown.
+pub async fn read_transactions_async<R, U>(
+    reader: R,
+    source: InputSource,
+    user: &mut U,
+    options: ReadOptions<'_>,
+) -> Result<ReadReport, anyhow::Error>
+where
+    R: AsyncRead + Unpin,
+    U: AsyncTransactionUser,
+{
+    let mut failures = Failures::new(source, options);
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    let mut position = Position::new(0, 1);
+    let mut headers = csv::StringRecord::new();
+    loop {
+        let read = next_line(&mut reader, &mut line).await?;
+        if read == 0 {
+            break;
+        }
+        let start = position;
+        position = Position::new(position.byte() + read as u64, position.line() + 1);
+        let content = String::from_utf8_lossy(&line);
+        if start.line() == 1 {
+            let header = std::str::from_utf8(&line).map_err(|e| e.to_string());
+            match header.and_then(|header| parse_line(header).map_err(|e| e.to_string())) {
+                Ok(header) => headers = header,
+                Err(message) => {
+                    let error = failures.malformed_row(start, &content, &message);
+                    failures.malformed(&headers, &headers, error)?;
+                }
+            }
+            continue;
+        }
+        if content.trim().is_empty() {
+            continue;
+        }
+        match parse_row(&line, &headers) {
+            Ok((tx, record)) => {
+                let (txtype, client, id) = (tx.txtype, tx.client, tx.id);
+                if let Err(e) = apply(tx, source, user).await {
+                    let error = RowError::new(e, start, source).with_tx(txtype, client, id);
+                    failures.rejected(&headers, &record, error)?;
+                }
+            }
+            Err((record, message)) => {
+                let error = failures.malformed_row(start, &content, &message);
+                failures.malformed(&headers, &record, error)?;
+            }
+        }
+    }
+    Ok(failures.finish())
+}
+
+/// Fields of a transaction from a stream, as they would have been read from CSV, for the
+/// rejects
+fn tx_fields(tx: &Transaction) -> (csv::StringRecord, csv::StringRecord) {
+    let headers = [
+        "type",
+        "client",
+        "tx",
+        "amount",
+        "operator",
+        "reason",
+        "timestamp",
+    ];
+    let record = [
+        tx.txtype.name().to_owned(),
+        tx.client.to_string(),
+        tx.id.to_string(),
+        tx.amount
+            .map(|amount| amount.to_string())
+            .unwrap_or_default(),
+        tx.operator.clone().unwrap_or_default(),
+        tx.reason.clone().unwrap_or_default(),
+        tx.timestamp
+            .map(|timestamp| timestamp.to_string())
+            .unwrap_or_default(),
+    ];
+    (headers.into_iter().collect(), record.into_iter().collect())
+}
+
+/// Same as `read_transactions_async`, from a `Stream` of already deserialized
+/// transactions: the next one is only polled once the previous one was handled. Failed
+/// transactions are handled according to `options` too, the first one of the stream
+/// being reported as line 1 (at byte 0, as there are no bytes).
+pub async fn read_transactions_stream<S, U>(
+    mut stream: S,
+    source: InputSource,
+    user: &mut U,
+    options: ReadOptions<'_>,
+) -> Result<ReadReport, anyhow::Error>
+where
+    S: Stream<Item = Transaction> + Unpin,
+    U: AsyncTransactionUser,
+{
+    let mut failures = Failures::new(source, options);
+    let mut line = 0;
+    while let Some(tx) = poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await {
+        line += 1;
+        let (txtype, client, id) = (tx.txtype, tx.client, tx.id);
+        let fields = failures.has_rejects().then(|| tx_fields(&tx));
+        if let Err(e) = apply(tx, source, user).await {
+            let error =
+                RowError::new(e, Position::new(0, line), source).with_tx(txtype, client, id);
+            let (headers, record) = fields.unwrap_or_default();
+            failures.rejected(&headers, &record, error)?;
+        }
+    }
+    Ok(failures.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        read_transactions_async, read_transactions_stream, AsyncAdapter, AsyncTransactionUser,
+    };
+    use crate::{
+        compute::Accounts,
+        data::{Error, Format, InputSource, RowError, Transaction, TxType, TypedTransaction},
+        read::{read_transactions, ErrorPolicy, ReadOptions, TransactionUser},
+        write::RejectWriter,
+    };
+    use futures_core::Stream;
+    use rust_decimal_macros::dec;
+    use std::{
+        future::Future,
+        pin::{pin, Pin},
+        task::{Context, Poll, Waker},
+    };
+
+    /// Unlike the shared fixture, with a blank line to skip
+    const TRANSACTIONS_CSV: &[u8] = b"\
+type,       client, tx, amount
+deposit,    1,      1,  1.0
+deposit,    2,      2,  2.0
+
+dispute,    1,      1,
+withdrawal, 2,      3,  5.0
+withdrawal, 2,      4,  1.5
+chargeback, 1,      1,
+";
+
+    /// Good enough for futures which are never woken up from elsewhere
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    /// Yields once before applying each transaction, like a user waiting on I/O would
+    struct Slow(Accounts);
+
+    impl AsyncTransactionUser for Slow {
+        async fn use_tx(&mut self, tx: TypedTransaction) -> Result<(), Error> {
+            let mut yielded = false;
+            std::future::poll_fn(|cx| {
+                if yielded {
+                    Poll::Ready(())
+                } else {
+                    yielded = true;
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+            })
+            .await;
+            self.0.use_tx(tx)
+        }
+    }
+
+    struct Iter(std::vec::IntoIter<Transaction>);
+
+    impl Stream for Iter {
+        type Item = Transaction;
+        fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Poll::Ready(self.0.next())
+        }
+    }
+
+    #[test]
+    fn read_async_tx() {
+        let mut expected = Accounts::new();
+        read_transactions(
+            TRANSACTIONS_CSV,
+            InputSource::User,
+            &mut expected,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        let mut user = AsyncAdapter(Accounts::new());
+        block_on(read_transactions_async(
+            TRANSACTIONS_CSV,
+            InputSource::User,
+            &mut user,
+            ReadOptions::default(),
+        ))
+        .unwrap();
+        assert_eq!(user.0, expected);
+        let mut user = Slow(Accounts::new());
+        block_on(read_transactions_async(
+            TRANSACTIONS_CSV,
+            InputSource::User,
+            &mut user,
+            ReadOptions::default(),
+        ))
+        .unwrap();
+        assert_eq!(user.0, expected);
+    }
+    #[test]
+    fn read_async_failures() {
+        let transactions_csv = b"\
+type,       client, tx, amount
+deposit,    1,      1,  1.0
+withdrawal, 1,      2,  5.0
+deposit,    1,      3,  lots
+deposit,    1,      4,  1.0,  2.0
+lock,       1,      5,
+deposit,    1,      6,  2.0
+";
+        // failures go the same way as with the blocking reader
+        let mut expected = RejectWriter::new(Vec::new(), Format::Csv);
+        read_transactions(
+            &transactions_csv[..],
+            InputSource::User,
+            &mut Accounts::new(),
+            ReadOptions::default().with_rejects(&mut expected),
+        )
+        .unwrap();
+        let mut rejects = RejectWriter::new(Vec::new(), Format::Csv);
+        let mut user = AsyncAdapter(Accounts::new());
+        let read = read_transactions_async(
+            &transactions_csv[..],
+            InputSource::User,
+            &mut user,
+            ReadOptions::default().with_rejects(&mut rejects),
+        );
+        fn is_send<F: Send>(future: F) -> F {
+            future
+        }
+        let report = block_on(is_send(read)).unwrap();
+        assert_eq!(user.0.account(1).unwrap().total(), dec!(3));
+        let failed: Vec<_> = report
+            .errors()
+            .iter()
+            .map(|row| (row.line(), row.error().code(), row.id()))
+            .collect();
+        assert_eq!(
+            failed,
+            [
+                (3, "insufficient_funds", Some(2)),
+                (4, "malformed_row", None),
+                (5, "malformed_row", None),
+                (6, "admin_only", Some(5)),
+            ]
+        );
+        assert_eq!(
+            report.errors()[1].content(),
+            Some("deposit,    1,      3,  lots")
+        );
+        assert_eq!(
+            rejects.into_inner().unwrap(),
+            expected.into_inner().unwrap()
+        );
+        for (policy, line) in [
+            (ErrorPolicy::Abort, 3),
+            (ErrorPolicy::SkipMalformedRows, 3),
+            (ErrorPolicy::AbortAfter(3), 5),
+        ] {
+            let error = block_on(read_transactions_async(
+                &transactions_csv[..],
+                InputSource::User,
+                &mut AsyncAdapter(Accounts::new()),
+                ReadOptions::default().with_policy(policy),
+            ))
+            .unwrap_err();
+            assert_eq!(error.downcast_ref::<RowError>().unwrap().line(), line);
+        }
+        // a header which can't be read leaves nothing readable
+        let error = block_on(read_transactions_async(
+            &b"type,\xff\ndeposit,1\n"[..],
+            InputSource::User,
+            &mut AsyncAdapter(Accounts::new()),
+            ReadOptions::default().with_policy(ErrorPolicy::AbortAfter(2)),
+        ))
+        .unwrap_err();
+        assert_eq!(error.downcast_ref::<RowError>().unwrap().line(), 2);
+    }
+    #[test]
+    fn read_stream_tx() {
+        let transactions = || {
+            Iter(
+                vec![
+                    Transaction::new(TxType::Deposit, 1, 1).with_amount(dec!(3)),
+                    Transaction::new(TxType::Withdrawal, 1, 2).with_amount(dec!(1)),
+                    // missing amount
+                    Transaction::new(TxType::Deposit, 1, 3),
+                    Transaction::new(TxType::Unlock, 1, 4),
+                ]
+                .into_iter(),
+            )
+        };
+        let mut user = Slow(Accounts::new());
+        let mut rejects = RejectWriter::new(Vec::new(), Format::Csv);
+        let report = block_on(read_transactions_stream(
+            transactions(),
+            InputSource::User,
+            &mut user,
+            ReadOptions::default().with_rejects(&mut rejects),
+        ))
+        .unwrap();
+        assert_eq!(user.0.account(1).unwrap().total(), dec!(2));
+        assert_eq!(user.0.txset.transactions.len(), 2);
+        let failed: Vec<_> = report
+            .errors()
+            .iter()
+            .map(|row| (row.line(), row.error().code(), row.id()))
+            .collect();
+        assert_eq!(
+            failed,
+            [(3, "missing_amount", Some(3)), (4, "admin_only", Some(4))]
+        );
+        assert_eq!(
+            String::from_utf8(rejects.into_inner().unwrap()).unwrap(),
+            "\
+type,client,tx,amount,operator,reason,timestamp,line,error,message,content
+deposit,1,3,,,,,3,missing_amount,Transaction amount is missing for dispute/withdrawal,
+unlock,1,4,,,,,4,admin_only,Administrative transaction from a non-admin source,
+"
+        );
+        // the policy stops the stream
+        let mut user = Slow(Accounts::new());
+        let error = block_on(read_transactions_stream(
+            transactions(),
+            InputSource::User,
+            &mut user,
+            ReadOptions::default().with_policy(ErrorPolicy::Abort),
+        ))
+        .unwrap_err();
+        assert_eq!(error.downcast_ref::<RowError>().unwrap().line(), 3);
+        assert_eq!(user.0.txset.transactions.len(), 2);
+    }
+}
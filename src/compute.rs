@@ -1,144 +1,318 @@
 use crate::{
-    data::{Account, ClientId, Error, Transaction, TxId, TxType::*},
+    data::{
+        Account, ClientId, Error, StoredTx, Timestamp, TxId, TxState, TxType, TypedTransaction,
+        TypedTransaction::*,
+    },
+    ledger::Ledger,
     read::TransactionUser,
+    store::{AccountStore, MemoryTxStore, TxIdSet, TxStore},
 };
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Which transactions can be disputed. By default only deposits can, as in the specs;
+/// `DepositsAndWithdrawals` also allows disputing a withdrawal (e.g. for card-not-present
+/// fraud), in which case the withdrawn amount is provisionally credited to `held` and is
+/// refunded to `available` on chargeback.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisputePolicy {
+    #[default]
+    DepositsOnly,
+    DepositsAndWithdrawals,
+}
 
 /// This is where accounts are store; they are created on the fly when reading the
 /// transactions. The exercise was single-threaded so no protections for MT.
-#[derive(Debug)]
-pub(crate) struct Accounts {
-    pub accounts: HashMap<ClientId, Account>,
-    txset: HashMap<TxId, Transaction>,
+/// Accounts are kept ordered by client id, so they're always output in the same order.
+/// The deposits and withdrawals are kept in a `TxStore`, and the accounts themselves in an
+/// `AccountStore`, both in memory by default.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Accounts<S = MemoryTxStore, A = BTreeMap<ClientId, Account>> {
+    pub(crate) accounts: A,
+    pub(crate) txset: S,
+    pub(crate) policy: DisputePolicy,
+    /// Accepted administrative transactions, for auditing purposes
+    pub(crate) audit: Vec<TypedTransaction>,
+    /// Journal of every change to the accounts, if asked for
+    pub(crate) ledger: Option<Ledger>,
+    /// How long after being made a transaction can be disputed, if limited
+    pub(crate) window: Option<Timestamp>,
+    /// Ids of the transactions dropped by `evict_expired`, so they can't be reused
+    pub(crate) evicted: TxIdSet,
 }
 
 impl Accounts {
+    /// Empty set of accounts, with the default `DisputePolicy`
     pub fn new() -> Self {
+        Self::with_tx_store(MemoryTxStore::default())
+    }
+}
+
+impl<S: TxStore> Accounts<S> {
+    /// Empty set of accounts, keeping transactions in `store` which should be empty too
+    pub fn with_tx_store(store: S) -> Self {
+        Self::with_stores(store, BTreeMap::new())
+    }
+    /// Account of a client, if any transaction of theirs succeeded
+    pub fn account(&self, client: ClientId) -> Option<&Account> {
+        self.accounts.get(&client)
+    }
+    /// All the accounts, ordered by client id
+    pub fn accounts(&self) -> impl Iterator<Item = &Account> {
+        self.accounts.values()
+    }
+}
+
+impl<S: TxStore, A: AccountStore> Accounts<S, A> {
+    /// Empty set of accounts, keeping transactions in `txset` and accounts in `accounts`,
+    /// which should both be empty too
+    pub fn with_stores(txset: S, accounts: A) -> Self {
         Self {
-            accounts: HashMap::new(),
-            txset: HashMap::new(),
+            accounts,
+            txset,
+            policy: DisputePolicy::default(),
+            audit: Vec::new(),
+            ledger: None,
+            window: None,
+            evicted: TxIdSet::default(),
         }
     }
+    pub fn with_dispute_policy(mut self, policy: DisputePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+    /// Only allows disputing a transaction up to `window` seconds after it was made, as card
+    /// schemes do. This only applies when both the transaction and its dispute have a
+    /// timestamp; `evict_expired` can then drop the transactions which can't be disputed
+    /// anymore.
+    pub fn with_dispute_window(mut self, window: Timestamp) -> Self {
+        self.window = Some(window);
+        self
+    }
+    /// Drops the stored transactions made more than the dispute window before `now`, to
+    /// reclaim memory; those under dispute are kept, so they can still be resolved.
+    ///
+    /// Beware that an evicted transaction is then just unknown: disputing it fails with
+    /// `TransactionNotFound`. Only its id is remembered, in a bitmap (8 KiB per range of
+    /// 65536 ids), so it can't be reused by a new transaction.
+    pub fn evict_expired(&mut self, now: Timestamp) -> Result<usize, Error> {
+        let Some(window) = self.window else {
+            return Ok(0);
+        };
+        let evicted = &mut self.evicted;
+        self.txset.retain(&mut |id, tx| {
+            let keep = tx.state == TxState::Disputed || !expired(window, tx.timestamp, Some(now));
+            if !keep {
+                evicted.insert(id);
+            }
+            keep
+        })
+    }
+    /// Keeps a `Ledger` of every change to the accounts. It grows with each accepted
+    /// transaction, so it's off by default.
+    pub fn with_ledger(mut self) -> Self {
+        self.ledger.get_or_insert_with(Ledger::default);
+        self
+    }
+    /// Where the accounts are kept, to get at them whatever the `AccountStore`
+    pub fn account_store(&self) -> &A {
+        &self.accounts
+    }
+    /// Administrative transactions accepted so far, in order
+    pub fn audit(&self) -> &[TypedTransaction] {
+        &self.audit
+    }
+    /// Journal of the changes to the accounts, if enabled by `with_ledger`
+    pub fn ledger(&self) -> Option<&Ledger> {
+        self.ledger.as_ref()
+    }
 }
 
-/// This is where the business logic stands. Maybe I could have factorized some
-/// repeated idioms into their own little function, like:
-/// ```rust
-/// if self.txset.insert(tx.id, tx).is_some() {
-///     return Err(Error::DuplicateTransaction(tx.id));
-/// }
-/// ```
-/// into something like `self.add_tx(tx)?;` but I'm not even sure the added code would
-/// have made the boilerplate any more clear. YMMV.
-impl TransactionUser for Accounts {
-    fn use_tx(&mut self, tx: Transaction) -> Result<(), Error> {
-        if tx.amount.unwrap_or_default().is_sign_negative() {
-            return Err(Error::NegativeAmount);
-        }
-        let account = self.accounts.entry(tx.client).or_insert(Account {
-            client: tx.client,
+impl Default for Accounts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Finds the stored transaction a dispute/resolve/chargeback refers to, checking it's
+/// allowed to be disputed at all.
+fn disputed_tx<S: TxStore>(
+    txset: &S,
+    policy: DisputePolicy,
+    client: ClientId,
+    id: TxId,
+) -> Result<StoredTx, Error> {
+    let stored = txset.get(id)?.ok_or(Error::TransactionNotFound(id))?;
+    match (stored.txtype, policy) {
+        (TxType::Deposit, _) | (TxType::Withdrawal, DisputePolicy::DepositsAndWithdrawals) => {}
+        _ => return Err(Error::WrongDispute),
+    }
+    if stored.client != client {
+        return Err(Error::DisputeMismatch);
+    }
+    Ok(stored)
+}
+
+/// Whether a transaction made at `made` can't be disputed anymore at `now`
+fn expired(window: Timestamp, made: Option<Timestamp>, now: Option<Timestamp>) -> bool {
+    match (made, now) {
+        (Some(made), Some(now)) => now.saturating_sub(made) > window,
+        _ => false,
+    }
+}
+
+/// Checks a new deposit or withdrawal doesn't reuse the id of an existing transaction, or
+/// of an evicted one.
+fn check_new_tx<S: TxStore>(txset: &S, evicted: &TxIdSet, id: TxId) -> Result<(), Error> {
+    if evicted.contains(id) || txset.get(id)?.is_some() {
+        return Err(Error::DuplicateTransaction(id));
+    }
+    Ok(())
+}
+
+/// This is where the business logic stands. Since a `TypedTransaction` can't be missing
+/// its amount (or have one it shouldn't), only the business rules are checked here.
+///
+/// Accounting rules for disputes, depending on the disputed transaction:
+///
+/// | disputed   | dispute              | resolve              | chargeback                     |
+/// |------------|----------------------|----------------------|--------------------------------|
+/// | deposit    | available -> held    | held -> available    | held removed, account locked   |
+/// | withdrawal | held credited        | held removed         | held -> available, locked      |
+///
+/// Administrative transactions (`lock`, `unlock`, `close`) bypass the locked check, so that
+/// an account can be reopened e.g. after a reversed chargeback. A closed account can't be
+/// reopened.
+///
+/// Applying a transaction is atomic: we work on a copy of the account, and every change
+/// (to the account, `txset` or `audit`) is only made once nothing can fail anymore, so a
+/// rejected transaction leaves `Accounts` untouched. The stores can fail too (e.g. when on
+/// disk), so they're written last: the account first, then the transaction, putting the
+/// account back as it was if the latter fails.
+impl<S: TxStore, A: AccountStore> TransactionUser for Accounts<S, A> {
+    fn use_tx(&mut self, tx: TypedTransaction) -> Result<(), Error> {
+        self.use_tx_at(tx, None)
+    }
+    fn use_tx_at(
+        &mut self,
+        tx: TypedTransaction,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), Error> {
+        let (client, id, txtype) = (tx.client(), tx.id(), tx.txtype());
+        let before = self.accounts.get(client)?;
+        let mut account = before.clone().unwrap_or(Account {
+            client,
             ..Account::default()
         });
-        // FIXME: the spec doesn't say when an account is to be unlocked,
-        // that means that after a chargeback an account is basically dead.
-        // If some operation is able to unlock the account, this test should
-        // move in the appropriate operations.
-        if account.locked {
+        // administrative transactions act on existing accounts, they don't open any
+        if txtype.is_admin() && before.is_none() {
+            return Err(Error::AccountNotFound(client));
+        }
+        if account.closed {
+            return Err(Error::AccountClosed);
+        }
+        // The spec doesn't say when an account is to be unlocked, so only an
+        // administrative transaction can do that.
+        if account.locked && !txtype.is_admin() {
             return Err(Error::AccountLocked);
         }
-        match tx.txtype {
-            Deposit => {
-                if self.txset.insert(tx.id, tx).is_some() {
-                    return Err(Error::DuplicateTransaction(tx.id));
-                }
-                let amount = tx.amount.ok_or(Error::MissingAmount)?;
-                account.available += amount;
+        // what's to be written to `txset`, if anything
+        let stored = match tx {
+            Deposit { client, id, amount } => {
+                check_new_tx(&self.txset, &self.evicted, id)?;
+                account.update(account.available.checked_add(amount)?, account.held)?;
+                let stored = StoredTx::new(TxType::Deposit, client, amount);
+                Some(stored.with_timestamp(timestamp))
             }
-            Withdrawal => {
-                if self.txset.insert(tx.id, tx).is_some() {
-                    return Err(Error::DuplicateTransaction(tx.id));
-                }
-                let amount = tx.amount.ok_or(Error::MissingAmount)?;
-                if account.available < amount {
-                    return Err(Error::InsufficientFunds {
-                        asked: amount,
-                        available: account.available,
-                    });
-                }
-                account.available -= amount;
+            Withdrawal { client, id, amount } => {
+                check_new_tx(&self.txset, &self.evicted, id)?;
+                account.update(account.available.checked_sub(amount)?, account.held)?;
+                let stored = StoredTx::new(TxType::Withdrawal, client, amount);
+                Some(stored.with_timestamp(timestamp))
             }
-            Dispute => {
-                if tx.amount.is_some() {
-                    return Err(Error::UnattendedforAmount);
-                }
-                let tx = self
-                    .txset
-                    .get(&tx.id)
-                    .ok_or(Error::TransactionNotFound(tx.id))?;
-                if tx.txtype != Deposit {
-                    return Err(Error::WrongDispute);
-                }
-                if tx.client != account.client {
-                    return Err(Error::DisputeMismatch);
-                }
-                let amount = tx.amount.ok_or(Error::MissingAmount)?;
-                if account.available < amount {
-                    return Err(Error::InsufficientFunds {
-                        asked: amount,
-                        available: account.available,
-                    });
+            Dispute { client, id } => {
+                let stored = disputed_tx(&self.txset, self.policy, client, id)?;
+                if let Some(window) = self.window {
+                    if expired(window, stored.timestamp, timestamp) {
+                        return Err(Error::DisputeWindowExpired(id));
+                    }
                 }
-                account.available -= amount;
-                account.held += amount;
+                let amount = stored.amount;
+                let available = match stored.txtype {
+                    TxType::Deposit => account.available.checked_sub(amount)?,
+                    _ => account.available,
+                };
+                let state = stored.state.dispute()?;
+                account.update(available, account.held.checked_add(amount)?)?;
+                Some(stored.with_state(state))
             }
-            Resolve => {
-                if tx.amount.is_some() {
-                    return Err(Error::UnattendedforAmount);
-                }
-                let tx = self
-                    .txset
-                    .get(&tx.id)
-                    .ok_or(Error::TransactionNotFound(tx.id))?;
-                if tx.txtype != Deposit {
-                    return Err(Error::WrongDispute);
-                }
-                if tx.client != account.client {
-                    return Err(Error::DisputeMismatch);
-                }
-                let amount = tx.amount.ok_or(Error::MissingAmount)?;
-                if account.held < amount {
-                    return Err(Error::InsufficientFunds {
-                        asked: amount,
-                        available: account.held,
-                    });
-                }
-                account.available += amount;
-                account.held -= amount;
+            Resolve { client, id } => {
+                let stored = disputed_tx(&self.txset, self.policy, client, id)?;
+                let amount = stored.amount;
+                let held = account.held.checked_sub(amount)?;
+                let available = match stored.txtype {
+                    TxType::Deposit => account.available.checked_add(amount)?,
+                    _ => account.available,
+                };
+                let state = stored.state.resolve()?;
+                account.update(available, held)?;
+                Some(stored.with_state(state))
             }
-            Chargeback => {
-                if tx.amount.is_some() {
-                    return Err(Error::UnattendedforAmount);
-                }
-                let tx = self
-                    .txset
-                    .get(&tx.id)
-                    .ok_or(Error::TransactionNotFound(tx.id))?;
-                if tx.txtype != Deposit {
-                    return Err(Error::WrongDispute);
+            Chargeback { client, id } => {
+                let stored = disputed_tx(&self.txset, self.policy, client, id)?;
+                let amount = stored.amount;
+                let held = account.held.checked_sub(amount)?;
+                let available = match stored.txtype {
+                    TxType::Withdrawal => account.available.checked_add(amount)?,
+                    _ => account.available,
+                };
+                let state = stored.state.chargeback()?;
+                account.update(available, held)?;
+                account.locked = true;
+                Some(stored.with_state(state))
+            }
+            Lock { .. } => {
+                if account.locked {
+                    return Err(Error::AccountLocked);
                 }
-                if tx.client != account.client {
-                    return Err(Error::DisputeMismatch);
+                account.locked = true;
+                None
+            }
+            Unlock { .. } => {
+                if !account.locked {
+                    return Err(Error::AccountNotLocked);
                 }
-                let amount = tx.amount.ok_or(Error::MissingAmount)?;
-                if account.held < amount {
-                    return Err(Error::InsufficientFunds {
-                        asked: amount,
-                        available: account.held,
-                    });
+                account.locked = false;
+                None
+            }
+            Close { .. } => {
+                if !account.held.is_zero() {
+                    return Err(Error::FundsHeld);
                 }
-                account.held -= amount;
                 account.locked = true;
+                account.closed = true;
+                None
             }
+        };
+        self.accounts.upsert(account.clone())?;
+        if let Some(stored) = stored {
+            if let Err(e) = self.txset.put(id, stored) {
+                match &before {
+                    Some(before) => self.accounts.upsert(before.clone())?,
+                    None => self.accounts.remove(client)?,
+                }
+                return Err(e);
+            }
+        }
+        if let Some(ledger) = &mut self.ledger {
+            let before = before.unwrap_or(Account {
+                client,
+                ..Account::default()
+            });
+            ledger.record(id, txtype, &before, &account);
+        }
+        if txtype.is_admin() {
+            self.audit.push(tx);
         }
         Ok(())
     }
@@ -147,31 +321,31 @@ impl TransactionUser for Accounts {
 #[cfg(test)]
 mod tests {
     use crate::{
-        data::{Account, Error, Transaction, TxType::*},
+        data::{amount, Account, AdminInfo, Error, StoredTx, TxId, TypedTransaction::*},
         read::TransactionUser,
+        store::{MemoryTxStore, TxStore},
     };
-    use rust_decimal_macros::dec;
 
-    use super::Accounts;
+    use super::{Accounts, DisputePolicy};
 
     #[test]
     fn test_deposit() {
         let mut accounts = Accounts::new();
         accounts
-            .use_tx(Transaction {
-                txtype: Deposit,
+            .use_tx(Deposit {
                 client: 5,
                 id: 1,
-                amount: Some(dec!(100)),
+                amount: amount!(100),
             })
             .unwrap();
         assert_eq!(
             accounts.accounts[&5],
             Account {
                 client: 5,
-                available: dec!(100),
-                held: dec!(0),
+                available: amount!(100),
+                held: amount!(0),
                 locked: false,
+                closed: false,
             },
         );
     }
@@ -179,28 +353,27 @@ mod tests {
     fn test_withdrawal() {
         let mut accounts = Accounts::new();
         accounts
-            .use_tx(Transaction {
-                txtype: Deposit,
+            .use_tx(Deposit {
                 client: 5,
                 id: 1,
-                amount: Some(dec!(100)),
+                amount: amount!(100),
             })
             .unwrap();
         accounts
-            .use_tx(Transaction {
-                txtype: Withdrawal,
+            .use_tx(Withdrawal {
                 client: 5,
                 id: 2,
-                amount: Some(dec!(60)),
+                amount: amount!(60),
             })
             .unwrap();
         assert_eq!(
             accounts.accounts[&5],
             Account {
                 client: 5,
-                available: dec!(40),
-                held: dec!(0),
+                available: amount!(40),
+                held: amount!(0),
                 locked: false,
+                closed: false,
             },
         );
     }
@@ -208,297 +381,726 @@ mod tests {
     fn test_dispute() {
         let mut accounts = Accounts::new();
         accounts
-            .use_tx(Transaction {
-                txtype: Deposit,
+            .use_tx(Deposit {
                 client: 5,
                 id: 1,
-                amount: Some(dec!(100)),
+                amount: amount!(100),
             })
             .unwrap();
+        accounts.use_tx(Dispute { client: 5, id: 1 }).unwrap();
+        assert_eq!(
+            accounts.accounts[&5],
+            Account {
+                client: 5,
+                available: amount!(0),
+                held: amount!(100),
+                locked: false,
+                closed: false,
+            },
+        );
+    }
+    #[test]
+    fn test_resolve() {
+        let mut accounts = Accounts::new();
         accounts
-            .use_tx(Transaction {
-                txtype: Dispute,
+            .use_tx(Deposit {
                 client: 5,
                 id: 1,
-                amount: None,
+                amount: amount!(100),
             })
             .unwrap();
+        accounts.use_tx(Dispute { client: 5, id: 1 }).unwrap();
+        accounts.use_tx(Resolve { client: 5, id: 1 }).unwrap();
         assert_eq!(
             accounts.accounts[&5],
             Account {
                 client: 5,
-                available: dec!(0),
-                held: dec!(100),
+                available: amount!(100),
+                held: amount!(0),
                 locked: false,
+                closed: false,
             },
         );
     }
     #[test]
-    fn test_resolve() {
+    fn test_chargeback() {
         let mut accounts = Accounts::new();
         accounts
-            .use_tx(Transaction {
-                txtype: Deposit,
+            .use_tx(Deposit {
                 client: 5,
                 id: 1,
-                amount: Some(dec!(100)),
+                amount: amount!(100),
             })
             .unwrap();
+        accounts.use_tx(Dispute { client: 5, id: 1 }).unwrap();
+        accounts.use_tx(Chargeback { client: 5, id: 1 }).unwrap();
+        assert_eq!(
+            accounts.accounts[&5],
+            Account {
+                client: 5,
+                available: amount!(0),
+                held: amount!(0),
+                locked: true,
+                closed: false,
+            },
+        );
+    }
+    #[test]
+    fn test_withdrawal_insufficient_funds() {
+        let mut accounts = Accounts::new();
         accounts
-            .use_tx(Transaction {
-                txtype: Dispute,
+            .use_tx(Deposit {
                 client: 5,
                 id: 1,
-                amount: None,
+                amount: amount!(100),
             })
             .unwrap();
+        let before = accounts.clone();
+        assert_eq!(
+            accounts.use_tx(Withdrawal {
+                client: 5,
+                id: 2,
+                amount: amount!(200)
+            }),
+            Err(Error::InsufficientFunds {
+                asked: amount!(200),
+                available: amount!(100)
+            })
+        );
+        assert_eq!(accounts, before);
+    }
+    #[test]
+    fn test_locked_account() {
+        let mut accounts = Accounts::new();
         accounts
-            .use_tx(Transaction {
-                txtype: Resolve,
+            .use_tx(Deposit {
                 client: 5,
                 id: 1,
-                amount: None,
+                amount: amount!(100),
             })
             .unwrap();
+        accounts.accounts.get_mut(&5).unwrap().locked = true;
+        let before = accounts.clone();
         assert_eq!(
-            accounts.accounts[&5],
-            Account {
+            accounts.use_tx(Withdrawal {
                 client: 5,
-                available: dec!(100),
-                held: dec!(0),
-                locked: false,
-            },
+                id: 2,
+                amount: amount!(200)
+            }),
+            Err(Error::AccountLocked)
         );
+        assert_eq!(accounts, before);
     }
     #[test]
-    fn test_chargeback() {
+    fn test_duplicate_transaction() {
+        let mut accounts = Accounts::new();
+        accounts
+            .use_tx(Deposit {
+                client: 5,
+                id: 1,
+                amount: amount!(100),
+            })
+            .unwrap();
+        let before = accounts.clone();
+        assert_eq!(
+            accounts.use_tx(Withdrawal {
+                client: 5,
+                id: 1,
+                amount: amount!(200)
+            }),
+            Err(Error::DuplicateTransaction(1))
+        );
+        assert_eq!(accounts, before);
+    }
+    #[test]
+    fn test_transaction_not_found() {
+        let mut accounts = Accounts::new();
+        accounts
+            .use_tx(Deposit {
+                client: 5,
+                id: 1,
+                amount: amount!(100),
+            })
+            .unwrap();
+        let before = accounts.clone();
+        assert_eq!(
+            accounts.use_tx(Dispute { client: 5, id: 2 }),
+            Err(Error::TransactionNotFound(2))
+        );
+        assert_eq!(accounts, before);
+    }
+    #[test]
+    fn test_dispute_mismatch() {
         let mut accounts = Accounts::new();
         accounts
-            .use_tx(Transaction {
-                txtype: Deposit,
+            .use_tx(Deposit {
                 client: 5,
                 id: 1,
-                amount: Some(dec!(100)),
+                amount: amount!(100),
             })
             .unwrap();
+        let before = accounts.clone();
+        assert_eq!(
+            accounts.use_tx(Dispute { client: 2, id: 1 }),
+            Err(Error::DisputeMismatch)
+        );
+        assert_eq!(accounts, before);
+    }
+    #[test]
+    fn test_wrong_dispute() {
+        let mut accounts = Accounts::new();
         accounts
-            .use_tx(Transaction {
-                txtype: Dispute,
+            .use_tx(Deposit {
                 client: 5,
                 id: 1,
-                amount: None,
+                amount: amount!(100),
+            })
+            .unwrap();
+        accounts
+            .use_tx(Withdrawal {
+                client: 5,
+                id: 2,
+                amount: amount!(60),
             })
             .unwrap();
+        let before = accounts.clone();
+        assert_eq!(
+            accounts.use_tx(Dispute { client: 2, id: 2 }),
+            Err(Error::WrongDispute)
+        );
+        assert_eq!(accounts, before);
+    }
+    #[test]
+    fn test_already_disputed() {
+        let mut accounts = Accounts::new();
         accounts
-            .use_tx(Transaction {
-                txtype: Chargeback,
+            .use_tx(Deposit {
                 client: 5,
                 id: 1,
-                amount: None,
+                amount: amount!(100),
+            })
+            .unwrap();
+        accounts
+            .use_tx(Deposit {
+                client: 5,
+                id: 2,
+                amount: amount!(100),
             })
             .unwrap();
+        accounts.use_tx(Dispute { client: 5, id: 1 }).unwrap();
+        let before = accounts.clone();
+        assert_eq!(
+            accounts.use_tx(Dispute { client: 5, id: 1 }),
+            Err(Error::AlreadyDisputed)
+        );
+        assert_eq!(accounts, before);
+        accounts.use_tx(Resolve { client: 5, id: 1 }).unwrap();
+        let before = accounts.clone();
+        assert_eq!(
+            accounts.use_tx(Dispute { client: 5, id: 1 }),
+            Err(Error::AlreadyDisputed)
+        );
+        assert_eq!(accounts, before);
         assert_eq!(
             accounts.accounts[&5],
             Account {
                 client: 5,
-                available: dec!(0),
-                held: dec!(0),
-                locked: true,
+                available: amount!(200),
+                held: amount!(0),
+                locked: false,
+                closed: false,
             },
         );
     }
     #[test]
-    fn test_withdrawal_insufficient_funds() {
+    fn test_not_under_dispute() {
         let mut accounts = Accounts::new();
         accounts
-            .use_tx(Transaction {
-                txtype: Deposit,
+            .use_tx(Deposit {
                 client: 5,
                 id: 1,
-                amount: Some(dec!(100)),
+                amount: amount!(100),
             })
             .unwrap();
-        assert_eq!(
-            accounts.use_tx(Transaction {
-                txtype: Withdrawal,
+        accounts
+            .use_tx(Deposit {
                 client: 5,
                 id: 2,
-                amount: Some(dec!(200)),
-            }),
-            Err(Error::InsufficientFunds {
-                asked: dec!(200),
-                available: dec!(100)
+                amount: amount!(100),
             })
+            .unwrap();
+        accounts.use_tx(Dispute { client: 5, id: 2 }).unwrap();
+        // enough funds are held, but not for this transaction
+        let before = accounts.clone();
+        assert_eq!(
+            accounts.use_tx(Resolve { client: 5, id: 1 }),
+            Err(Error::NotUnderDispute)
+        );
+        assert_eq!(accounts, before);
+        let before = accounts.clone();
+        assert_eq!(
+            accounts.use_tx(Chargeback { client: 5, id: 1 }),
+            Err(Error::NotUnderDispute)
+        );
+        assert_eq!(accounts, before);
+        assert_eq!(
+            accounts.accounts[&5],
+            Account {
+                client: 5,
+                available: amount!(100),
+                held: amount!(100),
+                locked: false,
+                closed: false,
+            },
         );
     }
     #[test]
-    fn test_locked_account() {
+    fn test_withdrawal_dispute_policy() {
         let mut accounts = Accounts::new();
         accounts
-            .use_tx(Transaction {
-                txtype: Deposit,
+            .use_tx(Deposit {
                 client: 5,
                 id: 1,
-                amount: Some(dec!(100)),
+                amount: amount!(100),
             })
             .unwrap();
-        accounts.accounts.get_mut(&5).unwrap().locked = true;
-        assert_eq!(
-            accounts.use_tx(Transaction {
-                txtype: Withdrawal,
+        accounts
+            .use_tx(Withdrawal {
                 client: 5,
                 id: 2,
-                amount: Some(dec!(200)),
-            }),
-            Err(Error::AccountLocked)
+                amount: amount!(60),
+            })
+            .unwrap();
+        let before = accounts.clone();
+        assert_eq!(
+            accounts.use_tx(Dispute { client: 5, id: 2 }),
+            Err(Error::WrongDispute)
         );
+        assert_eq!(accounts, before);
     }
     #[test]
-    fn test_duplicate_transaction() {
-        let mut accounts = Accounts::new();
+    fn test_withdrawal_dispute_resolve() {
+        let mut accounts =
+            Accounts::new().with_dispute_policy(DisputePolicy::DepositsAndWithdrawals);
         accounts
-            .use_tx(Transaction {
-                txtype: Deposit,
+            .use_tx(Deposit {
                 client: 5,
                 id: 1,
-                amount: Some(dec!(100)),
+                amount: amount!(100),
+            })
+            .unwrap();
+        accounts
+            .use_tx(Withdrawal {
+                client: 5,
+                id: 2,
+                amount: amount!(60),
             })
             .unwrap();
+        accounts.use_tx(Dispute { client: 5, id: 2 }).unwrap();
+        assert_eq!(
+            accounts.accounts[&5],
+            Account {
+                client: 5,
+                available: amount!(40),
+                held: amount!(60),
+                locked: false,
+                closed: false,
+            },
+        );
+        accounts.use_tx(Resolve { client: 5, id: 2 }).unwrap();
         assert_eq!(
-            accounts.use_tx(Transaction {
-                txtype: Withdrawal,
+            accounts.accounts[&5],
+            Account {
+                client: 5,
+                available: amount!(40),
+                held: amount!(0),
+                locked: false,
+                closed: false,
+            },
+        );
+    }
+    #[test]
+    fn test_withdrawal_dispute_chargeback() {
+        let mut accounts =
+            Accounts::new().with_dispute_policy(DisputePolicy::DepositsAndWithdrawals);
+        accounts
+            .use_tx(Deposit {
                 client: 5,
                 id: 1,
-                amount: Some(dec!(200)),
-            }),
-            Err(Error::DuplicateTransaction(1))
+                amount: amount!(100),
+            })
+            .unwrap();
+        accounts
+            .use_tx(Withdrawal {
+                client: 5,
+                id: 2,
+                amount: amount!(60),
+            })
+            .unwrap();
+        accounts.use_tx(Dispute { client: 5, id: 2 }).unwrap();
+        accounts.use_tx(Chargeback { client: 5, id: 2 }).unwrap();
+        assert_eq!(
+            accounts.accounts[&5],
+            Account {
+                client: 5,
+                available: amount!(100),
+                held: amount!(0),
+                locked: true,
+                closed: false,
+            },
         );
     }
     #[test]
-    fn test_negative_amount() {
+    fn test_unlock_after_chargeback() {
         let mut accounts = Accounts::new();
+        for tx in [
+            Deposit {
+                client: 5,
+                id: 1,
+                amount: amount!(100),
+            },
+            Deposit {
+                client: 5,
+                id: 2,
+                amount: amount!(50),
+            },
+            Dispute { client: 5, id: 1 },
+            Chargeback { client: 5, id: 1 },
+        ] {
+            accounts.use_tx(tx).unwrap();
+        }
+        let unlock = Unlock {
+            client: 5,
+            id: 3,
+            admin: AdminInfo {
+                operator: "alice".into(),
+                reason: Some("chargeback reversed".into()),
+            },
+        };
+        accounts.use_tx(unlock.clone()).unwrap();
+        assert_eq!(accounts.audit[0], unlock);
+        assert_eq!(accounts.use_tx(unlock), Err(Error::AccountNotLocked));
+        accounts
+            .use_tx(Withdrawal {
+                client: 5,
+                id: 4,
+                amount: amount!(20),
+            })
+            .unwrap();
         assert_eq!(
-            accounts.use_tx(Transaction {
-                txtype: Deposit,
+            accounts.accounts[&5],
+            Account {
+                client: 5,
+                available: amount!(30),
+                held: amount!(0),
+                locked: false,
+                closed: false,
+            },
+        );
+    }
+    #[test]
+    fn test_lock_and_close() {
+        let mut accounts = Accounts::new();
+        accounts
+            .use_tx(Deposit {
                 client: 5,
                 id: 1,
-                amount: Some(dec!(-100)),
+                amount: amount!(100),
+            })
+            .unwrap();
+        accounts
+            .use_tx(Lock {
+                client: 5,
+                id: 2,
+                admin: AdminInfo {
+                    operator: "alice".into(),
+                    reason: None,
+                },
+            })
+            .unwrap();
+        let before = accounts.clone();
+        assert_eq!(
+            accounts.use_tx(Deposit {
+                client: 5,
+                id: 3,
+                amount: amount!(100)
             }),
-            Err(Error::NegativeAmount)
+            Err(Error::AccountLocked)
         );
+        assert_eq!(accounts, before);
+        accounts
+            .use_tx(Close {
+                client: 5,
+                id: 4,
+                admin: AdminInfo {
+                    operator: "bob".into(),
+                    reason: Some("customer request".into()),
+                },
+            })
+            .unwrap();
+        let before = accounts.clone();
         assert_eq!(
-            accounts.use_tx(Transaction {
-                txtype: Withdrawal,
+            accounts.use_tx(Unlock {
                 client: 5,
-                id: 2,
-                amount: Some(dec!(-100)),
+                id: 5,
+                admin: AdminInfo {
+                    operator: "alice".into(),
+                    reason: None
+                }
             }),
-            Err(Error::NegativeAmount)
+            Err(Error::AccountClosed)
+        );
+        assert_eq!(accounts, before);
+        assert_eq!(
+            accounts.accounts[&5],
+            Account {
+                client: 5,
+                available: amount!(100),
+                held: amount!(0),
+                locked: true,
+                closed: true,
+            },
         );
+        assert_eq!(accounts.audit.len(), 2);
     }
     #[test]
-    fn test_missing_amount() {
+    fn test_admin_unknown_client() {
         let mut accounts = Accounts::new();
+        let admin = || AdminInfo {
+            operator: "alice".into(),
+            reason: None,
+        };
         assert_eq!(
-            accounts.use_tx(Transaction {
-                txtype: Deposit,
-                client: 5,
+            accounts.use_tx(Lock {
+                client: 8,
                 id: 1,
-                amount: None,
+                admin: admin(),
+            }),
+            Err(Error::AccountNotFound(8))
+        );
+        assert_eq!(
+            accounts.use_tx(Close {
+                client: 9,
+                id: 2,
+                admin: admin(),
+            }),
+            Err(Error::AccountNotFound(9))
+        );
+        assert_eq!(
+            accounts.use_tx(Unlock {
+                client: 9,
+                id: 3,
+                admin: admin(),
             }),
-            Err(Error::MissingAmount)
+            Err(Error::AccountNotFound(9))
         );
+        // no account was opened for them
+        assert_eq!(accounts, Accounts::new());
+        assert!(accounts.audit.is_empty());
     }
     #[test]
-    fn test_unattended_amount() {
+    fn test_close_with_held_funds() {
         let mut accounts = Accounts::new();
         accounts
-            .use_tx(Transaction {
-                txtype: Deposit,
+            .use_tx(Deposit {
                 client: 5,
                 id: 1,
-                amount: Some(dec!(100)),
+                amount: amount!(100),
             })
             .unwrap();
+        accounts.use_tx(Dispute { client: 5, id: 1 }).unwrap();
+        let before = accounts.clone();
         assert_eq!(
-            accounts.use_tx(Transaction {
-                txtype: Dispute,
+            accounts.use_tx(Close {
                 client: 5,
-                id: 1,
-                amount: Some(dec!(100)),
+                id: 2,
+                admin: AdminInfo {
+                    operator: "bob".into(),
+                    reason: None
+                }
             }),
-            Err(Error::UnattendedforAmount)
+            Err(Error::FundsHeld)
         );
+        assert_eq!(accounts, before);
     }
     #[test]
-    fn test_transaction_not_found() {
+    fn test_overflow() {
         let mut accounts = Accounts::new();
         accounts
-            .use_tx(Transaction {
-                txtype: Deposit,
+            .use_tx(Deposit {
                 client: 5,
                 id: 1,
-                amount: Some(dec!(100)),
+                amount: amount!(50_000_000_000_000_000_000_000_000_000),
             })
             .unwrap();
+        let before = accounts.clone();
         assert_eq!(
-            accounts.use_tx(Transaction {
-                txtype: Dispute,
+            accounts.use_tx(Deposit {
                 client: 5,
                 id: 2,
-                amount: None,
+                amount: amount!(50_000_000_000_000_000_000_000_000_000),
             }),
-            Err(Error::TransactionNotFound(2))
+            Err(Error::Overflow)
+        );
+        assert_eq!(accounts, before);
+        assert_eq!(
+            accounts.accounts[&5].available,
+            amount!(50_000_000_000_000_000_000_000_000_000)
         );
     }
     #[test]
-    fn test_dispute_mismatch() {
+    fn test_rejected_tx_side_effects() {
         let mut accounts = Accounts::new();
+        let before = accounts.clone();
+        assert_eq!(
+            accounts.use_tx(Withdrawal {
+                client: 5,
+                id: 1,
+                amount: amount!(100),
+            }),
+            Err(Error::InsufficientFunds {
+                asked: amount!(100),
+                available: amount!(0)
+            })
+        );
+        assert_eq!(accounts, before);
+        assert!(accounts.accounts.is_empty());
+        // the id hasn't been burnt by the rejected withdrawal
         accounts
-            .use_tx(Transaction {
-                txtype: Deposit,
+            .use_tx(Deposit {
                 client: 5,
                 id: 1,
-                amount: Some(dec!(100)),
+                amount: amount!(100),
             })
             .unwrap();
+        assert_eq!(accounts.accounts[&5].available, amount!(100));
+    }
+    #[test]
+    fn test_dispute_window() {
+        let mut accounts = Accounts::new().with_dispute_window(100);
+        let deposit = |id| Deposit {
+            client: 5,
+            id,
+            amount: amount!(100),
+        };
+        accounts.use_tx_at(deposit(1), Some(1000)).unwrap();
+        accounts.use_tx_at(deposit(2), Some(1000)).unwrap();
+        accounts.use_tx(deposit(3)).unwrap();
+        let before = accounts.clone();
         assert_eq!(
-            accounts.use_tx(Transaction {
-                txtype: Dispute,
-                client: 2,
-                id: 1,
-                amount: None,
-            }),
-            Err(Error::DisputeMismatch)
+            accounts.use_tx_at(Dispute { client: 5, id: 1 }, Some(1101)),
+            Err(Error::DisputeWindowExpired(1))
         );
+        assert_eq!(accounts, before);
+        accounts
+            .use_tx_at(Dispute { client: 5, id: 2 }, Some(1100))
+            .unwrap();
+        // without both timestamps, there's nothing to check
+        accounts
+            .use_tx_at(Dispute { client: 5, id: 3 }, Some(5000))
+            .unwrap();
+        // the window only applies to disputes, not to settling them
+        accounts
+            .use_tx_at(Resolve { client: 5, id: 2 }, Some(5000))
+            .unwrap();
+        assert_eq!(accounts.accounts[&5].held, amount!(100));
     }
     #[test]
-    fn test_wrong_dispute() {
+    fn test_evict_expired() {
         let mut accounts = Accounts::new();
         accounts
-            .use_tx(Transaction {
-                txtype: Deposit,
+            .use_tx_at(
+                Deposit {
+                    client: 5,
+                    id: 1,
+                    amount: amount!(100),
+                },
+                Some(1000),
+            )
+            .unwrap();
+        assert_eq!(accounts.evict_expired(5000), Ok(0));
+        let mut accounts = accounts.with_dispute_window(100);
+        for id in 2..=4 {
+            let deposit = Deposit {
                 client: 5,
-                id: 1,
-                amount: Some(dec!(100)),
-            })
+                id,
+                amount: amount!(100),
+            };
+            accounts.use_tx_at(deposit, Some(1000)).unwrap();
+        }
+        accounts
+            .use_tx_at(Dispute { client: 5, id: 2 }, Some(1050))
             .unwrap();
         accounts
-            .use_tx(Transaction {
-                txtype: Withdrawal,
-                client: 5,
-                id: 2,
-                amount: Some(dec!(60)),
-            })
+            .use_tx_at(
+                Deposit {
+                    client: 5,
+                    id: 5,
+                    amount: amount!(100),
+                },
+                Some(1050),
+            )
+            .unwrap();
+        // 1, 3 and 4 are expired, 2 is under dispute
+        assert_eq!(accounts.evict_expired(1101), Ok(3));
+        assert!(accounts.txset.transactions.contains_key(&2));
+        assert!(accounts.txset.transactions.contains_key(&5));
+        accounts
+            .use_tx_at(Resolve { client: 5, id: 2 }, Some(1200))
             .unwrap();
         assert_eq!(
-            accounts.use_tx(Transaction {
-                txtype: Dispute,
-                client: 2,
-                id: 2,
-                amount: None,
-            }),
-            Err(Error::WrongDispute)
+            accounts.use_tx(Dispute { client: 5, id: 3 }),
+            Err(Error::TransactionNotFound(3))
+        );
+        // evicted ids can't be reused
+        let deposit = Deposit {
+            client: 6,
+            id: 3,
+            amount: amount!(100),
+        };
+        assert_eq!(
+            accounts.use_tx_at(deposit, Some(1200)),
+            Err(Error::DuplicateTransaction(3))
         );
+        assert!(accounts.account(6).is_none());
+    }
+    #[test]
+    fn test_store_failure() {
+        /// `TxStore` failing to store transaction 13
+        #[derive(Default)]
+        struct Failing(MemoryTxStore);
+        impl TxStore for Failing {
+            fn get(&self, id: TxId) -> Result<Option<StoredTx>, Error> {
+                self.0.get(id)
+            }
+            fn put(&mut self, id: TxId, tx: StoredTx) -> Result<(), Error> {
+                if id == 13 {
+                    return Err(Error::StoreFailure("disk full".into()));
+                }
+                self.0.put(id, tx)
+            }
+            fn retain(
+                &mut self,
+                keep: &mut dyn FnMut(TxId, &StoredTx) -> bool,
+            ) -> Result<usize, Error> {
+                self.0.retain(keep)
+            }
+        }
+        let mut accounts = Accounts::with_tx_store(Failing::default()).with_ledger();
+        let deposit = |client, id| Deposit {
+            client,
+            id,
+            amount: amount!(100),
+        };
+        let failure = Err(Error::StoreFailure("disk full".into()));
+        // new account
+        assert_eq!(accounts.use_tx(deposit(5, 13)), failure);
+        assert!(accounts.accounts.is_empty());
+        // existing account
+        accounts.use_tx(deposit(5, 1)).unwrap();
+        let before = accounts.accounts.clone();
+        assert_eq!(accounts.use_tx(deposit(5, 13)), failure);
+        assert_eq!(accounts.accounts, before);
+        assert_eq!(accounts.ledger().unwrap().last_sequence(), 1);
     }
 }
@@ -4,21 +4,130 @@ use thiserror::Error;
 
 pub type ClientId = u16;
 pub type TxId = u32;
+pub type OperatorId = String;
+/// Point in time, in seconds (e.g. since the Unix epoch, as long as it's always the same)
+pub type Timestamp = u64;
 
 pub const SIGNIFICANT_DIGITS: u32 = 4;
 
+/// An amount of currency. It's never negative and always has `SIGNIFICANT_DIGITS` decimals,
+/// which is enforced at construction; arithmetic is checked so we get an `Error` instead of
+/// a panic on overflow or if there's not enough to subtract from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(into = "Decimal", try_from = "Decimal")]
+pub struct Amount(Decimal);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(Decimal::from_parts(0, 0, 0, false, SIGNIFICANT_DIGITS));
+
+    /// Amounts too large to hold `SIGNIFICANT_DIGITS` decimals keep as many as they can.
+    pub fn new(mut amount: Decimal) -> Result<Self, Error> {
+        if amount.is_sign_negative() && !amount.is_zero() {
+            return Err(Error::NegativeAmount);
+        }
+        amount.set_sign_positive(true);
+        amount.rescale(SIGNIFICANT_DIGITS);
+        Ok(Self(amount))
+    }
+    pub fn is_zero(self) -> bool {
+        self.0.is_zero()
+    }
+    pub fn checked_add(self, other: Amount) -> Result<Amount, Error> {
+        self.0.checked_add(other.0).map(Self).ok_or(Error::Overflow)
+    }
+    /// Fails with `Error::InsufficientFunds` if `other` is larger than `self`
+    pub fn checked_sub(self, other: Amount) -> Result<Amount, Error> {
+        if self < other {
+            return Err(Error::InsufficientFunds {
+                asked: other,
+                available: self,
+            });
+        }
+        Ok(Self(self.0 - other.0))
+    }
+}
+
+/// Shorthand for building an `Amount` from a literal in tests
+#[cfg(test)]
+macro_rules! amount {
+    ($($t:tt)*) => {
+        crate::data::Amount::new(rust_decimal_macros::dec!($($t)*)).unwrap()
+    };
+}
+#[cfg(test)]
+pub(crate) use amount;
+
+impl Default for Amount {
+    fn default() -> Self {
+        Self::ZERO
+    }
+}
+
+impl TryFrom<Decimal> for Amount {
+    type Error = Error;
+
+    fn try_from(amount: Decimal) -> Result<Self, Error> {
+        Self::new(amount)
+    }
+}
+
+impl From<Amount> for Decimal {
+    fn from(amount: Amount) -> Self {
+        amount.0
+    }
+}
+
+impl std::fmt::Display for Amount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 /// This is our `Account` structure we work with. You'll note it has no `total` field
 /// because it's a kind of "virtual" field whose value is always `available + held`. So
 /// instead of manually maintaining an invariant everywhere, we'll just compute it at the only
 /// time we need it: at serialization time.
-/// See `AccountSerializer` for details
+/// See `AccountSerializer` for details, and `Account::update` for how we make sure
+/// computing it won't overflow.
+///
+/// A `closed` account is also `locked`, but can't be unlocked anymore.
 #[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
 #[serde(into = "AccountSerializer")]
-pub(crate) struct Account {
-    pub client: ClientId,
-    pub available: Decimal,
-    pub held: Decimal,
-    pub locked: bool,
+pub struct Account {
+    pub(crate) client: ClientId,
+    pub(crate) available: Amount,
+    pub(crate) held: Amount,
+    pub(crate) locked: bool,
+    pub(crate) closed: bool,
+}
+
+impl Account {
+    pub fn client(&self) -> ClientId {
+        self.client
+    }
+    pub fn available(&self) -> Amount {
+        self.available
+    }
+    pub fn held(&self) -> Amount {
+        self.held
+    }
+    pub fn total(&self) -> Decimal {
+        // can't overflow, see `Account::update`
+        Decimal::from(self.available) + Decimal::from(self.held)
+    }
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+    pub fn closed(&self) -> bool {
+        self.closed
+    }
+    /// Sets both balances at once, checking the resulting total can be represented.
+    pub(crate) fn update(&mut self, available: Amount, held: Amount) -> Result<(), Error> {
+        available.checked_add(held)?;
+        self.available = available;
+        self.held = held;
+        Ok(())
+    }
 }
 
 /// This is our proxy for serializing `Account`: it will compute its
@@ -26,8 +135,8 @@ pub(crate) struct Account {
 #[derive(Serialize)]
 pub(crate) struct AccountSerializer {
     pub client: ClientId,
-    pub available: Decimal,
-    pub held: Decimal,
+    pub available: Amount,
+    pub held: Amount,
     pub total: Decimal,
     pub locked: bool,
 }
@@ -36,7 +145,7 @@ impl From<Account> for AccountSerializer {
     fn from(account: Account) -> Self {
         Self {
             client: account.client,
-            total: account.available + account.held,
+            total: account.total(),
             available: account.available,
             held: account.held,
             locked: account.locked,
@@ -48,30 +157,440 @@ impl From<Account> for AccountSerializer {
 /// in the specs but makes sense, so it's enforced in the code. Also the spec isn't clear if
 /// zero amounts are allowed, so they are indeed allowed (even if that makes little sense, it
 /// does not seem like an impossible transaction).
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
-pub(crate) struct Transaction {
+/// The `operator` and `reason` columns are optional, and only used by administrative
+/// transactions. The `timestamp` column is optional too, see `Accounts::with_dispute_window`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Transaction {
     #[serde(rename = "type")]
-    pub txtype: TxType,
-    pub client: ClientId,
+    pub(crate) txtype: TxType,
+    pub(crate) client: ClientId,
     #[serde(rename = "tx")]
-    pub id: TxId,
-    pub amount: Option<Decimal>,
+    pub(crate) id: TxId,
+    pub(crate) amount: Option<Decimal>,
+    #[serde(default)]
+    pub(crate) operator: Option<OperatorId>,
+    #[serde(default)]
+    pub(crate) reason: Option<String>,
+    #[serde(default)]
+    pub(crate) timestamp: Option<Timestamp>,
+}
+
+/// `Transaction` is built like this, e.g.
+/// `Transaction::new(TxType::Deposit, 1, 1).with_amount(dec!(1.5))`.
+impl Transaction {
+    pub fn new(txtype: TxType, client: ClientId, id: TxId) -> Self {
+        Self {
+            txtype,
+            client,
+            id,
+            amount: None,
+            operator: None,
+            reason: None,
+            timestamp: None,
+        }
+    }
+    pub fn with_amount(mut self, amount: Decimal) -> Self {
+        self.amount = Some(amount);
+        self
+    }
+    pub fn with_operator(mut self, operator: impl Into<OperatorId>) -> Self {
+        self.operator = Some(operator.into());
+        self
+    }
+    pub fn with_reason(mut self, reason: impl Into<String>) -> Self {
+        self.reason = Some(reason.into());
+        self
+    }
+    pub fn with_timestamp(mut self, timestamp: Timestamp) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+    pub fn txtype(&self) -> TxType {
+        self.txtype
+    }
+    pub fn client(&self) -> ClientId {
+        self.client
+    }
+    pub fn id(&self) -> TxId {
+        self.id
+    }
+    pub fn amount(&self) -> Option<Decimal> {
+        self.amount
+    }
+    pub fn operator(&self) -> Option<&str> {
+        self.operator.as_deref()
+    }
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
+    }
+}
+
+/// Operator and reason recorded along with an administrative transaction
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdminInfo {
+    pub(crate) operator: OperatorId,
+    pub(crate) reason: Option<String>,
+}
+
+impl AdminInfo {
+    pub fn new(operator: impl Into<OperatorId>) -> Self {
+        Self {
+            operator: operator.into(),
+            reason: None,
+        }
+    }
+    pub fn with_reason(mut self, reason: impl Into<String>) -> Self {
+        self.reason = Some(reason.into());
+        self
+    }
+    pub fn operator(&self) -> &str {
+        &self.operator
+    }
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
+}
+
+/// A `Transaction` checked for consistency, which is what the business logic works with:
+/// only deposits and withdrawals carry an amount, only administrative transactions carry an
+/// operator, so invalid combinations can't even be represented.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TypedTransaction {
+    Deposit {
+        client: ClientId,
+        id: TxId,
+        amount: Amount,
+    },
+    Withdrawal {
+        client: ClientId,
+        id: TxId,
+        amount: Amount,
+    },
+    Dispute {
+        client: ClientId,
+        id: TxId,
+    },
+    Resolve {
+        client: ClientId,
+        id: TxId,
+    },
+    Chargeback {
+        client: ClientId,
+        id: TxId,
+    },
+    Lock {
+        client: ClientId,
+        id: TxId,
+        admin: AdminInfo,
+    },
+    Unlock {
+        client: ClientId,
+        id: TxId,
+        admin: AdminInfo,
+    },
+    Close {
+        client: ClientId,
+        id: TxId,
+        admin: AdminInfo,
+    },
+}
+
+impl TypedTransaction {
+    pub fn client(&self) -> ClientId {
+        match *self {
+            TypedTransaction::Deposit { client, .. }
+            | TypedTransaction::Withdrawal { client, .. }
+            | TypedTransaction::Dispute { client, .. }
+            | TypedTransaction::Resolve { client, .. }
+            | TypedTransaction::Chargeback { client, .. }
+            | TypedTransaction::Lock { client, .. }
+            | TypedTransaction::Unlock { client, .. }
+            | TypedTransaction::Close { client, .. } => client,
+        }
+    }
+    pub fn id(&self) -> TxId {
+        match *self {
+            TypedTransaction::Deposit { id, .. }
+            | TypedTransaction::Withdrawal { id, .. }
+            | TypedTransaction::Dispute { id, .. }
+            | TypedTransaction::Resolve { id, .. }
+            | TypedTransaction::Chargeback { id, .. }
+            | TypedTransaction::Lock { id, .. }
+            | TypedTransaction::Unlock { id, .. }
+            | TypedTransaction::Close { id, .. } => id,
+        }
+    }
+    pub fn txtype(&self) -> TxType {
+        match self {
+            TypedTransaction::Deposit { .. } => TxType::Deposit,
+            TypedTransaction::Withdrawal { .. } => TxType::Withdrawal,
+            TypedTransaction::Dispute { .. } => TxType::Dispute,
+            TypedTransaction::Resolve { .. } => TxType::Resolve,
+            TypedTransaction::Chargeback { .. } => TxType::Chargeback,
+            TypedTransaction::Lock { .. } => TxType::Lock,
+            TypedTransaction::Unlock { .. } => TxType::Unlock,
+            TypedTransaction::Close { .. } => TxType::Close,
+        }
+    }
+}
+
+/// This is the parsing boundary: a deserialized `Transaction` is checked here for the
+/// presence (or absence) of its amount and operator, and the amount is converted to an
+/// `Amount`.
+impl TryFrom<Transaction> for TypedTransaction {
+    type Error = Error;
+
+    fn try_from(tx: Transaction) -> Result<Self, Error> {
+        let Transaction {
+            txtype,
+            client,
+            id,
+            amount,
+            operator,
+            reason,
+            // handed to `TransactionUser::use_tx_at` separately
+            timestamp: _,
+        } = tx;
+        let admin = || -> Result<AdminInfo, Error> {
+            if amount.is_some() {
+                return Err(Error::UnattendedforAmount);
+            }
+            let operator = operator.ok_or(Error::MissingOperator)?;
+            Ok(AdminInfo { operator, reason })
+        };
+        let no_amount = || {
+            if amount.is_some() {
+                return Err(Error::UnattendedforAmount);
+            }
+            Ok(())
+        };
+        Ok(match txtype {
+            TxType::Deposit => TypedTransaction::Deposit {
+                client,
+                id,
+                amount: Amount::new(amount.ok_or(Error::MissingAmount)?)?,
+            },
+            TxType::Withdrawal => TypedTransaction::Withdrawal {
+                client,
+                id,
+                amount: Amount::new(amount.ok_or(Error::MissingAmount)?)?,
+            },
+            TxType::Dispute => {
+                no_amount()?;
+                TypedTransaction::Dispute { client, id }
+            }
+            TxType::Resolve => {
+                no_amount()?;
+                TypedTransaction::Resolve { client, id }
+            }
+            TxType::Chargeback => {
+                no_amount()?;
+                TypedTransaction::Chargeback { client, id }
+            }
+            TxType::Lock => TypedTransaction::Lock {
+                client,
+                id,
+                admin: admin()?,
+            },
+            TxType::Unlock => TypedTransaction::Unlock {
+                client,
+                id,
+                admin: admin()?,
+            },
+            TxType::Close => TypedTransaction::Close {
+                client,
+                id,
+                admin: admin()?,
+            },
+        })
+    }
 }
 
 /// Different types of transaction
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
-pub(crate) enum TxType {
+pub enum TxType {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    Lock,
+    Unlock,
+    Close,
 }
 
-/// Transaction error handling; these are just here to show how it's done and are
-/// incomplete for a real life use. For example, `InsufficientFunds` probably should tell us
-/// which transaction tried to withdraw the funds, and from which client account it is.
+impl TxType {
+    /// Name of the type, as in the `type` column
+    pub fn name(self) -> &'static str {
+        match self {
+            TxType::Deposit => "deposit",
+            TxType::Withdrawal => "withdrawal",
+            TxType::Dispute => "dispute",
+            TxType::Resolve => "resolve",
+            TxType::Chargeback => "chargeback",
+            TxType::Lock => "lock",
+            TxType::Unlock => "unlock",
+            TxType::Close => "close",
+        }
+    }
+    /// Administrative transactions are only accepted from an admin `InputSource`
+    pub fn is_admin(self) -> bool {
+        matches!(self, TxType::Lock | TxType::Unlock | TxType::Close)
+    }
+}
+
+/// Where transactions come from; only admin sources can issue administrative transactions.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum InputSource {
+    #[default]
+    User,
+    Admin,
+}
+
+impl InputSource {
+    pub fn check(self, tx: &Transaction) -> Result<(), Error> {
+        if tx.txtype.is_admin() && self != InputSource::Admin {
+            return Err(Error::AdminOnly);
+        }
+        Ok(())
+    }
+}
+
+/// Formats transactions can be read from, and accounts or rejects written to. JSON Lines
+/// objects have the same fields as the CSV columns; amounts are written as strings in both
+/// cases, to avoid any floating point rounding, but JSON numbers are read as written too.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Csv,
+    JsonLines,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "csv" => Some(Format::Csv),
+            "json" | "jsonl" | "ndjson" => Some(Format::JsonLines),
+            _ => None,
+        }
+    }
+    /// `.json`, `.jsonl` and `.ndjson` files are JSON Lines, anything else is CSV
+    pub fn from_path(path: &std::path::Path) -> Self {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(Format::from_name)
+            .unwrap_or_default()
+    }
+}
+
+/// Position in an input file: byte offset and line number (starting at 1)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    byte: u64,
+    line: u64,
+}
+
+impl Position {
+    pub fn new(byte: u64, line: u64) -> Self {
+        Self { byte, line }
+    }
+    pub fn byte(&self) -> u64 {
+        self.byte
+    }
+    pub fn line(&self) -> u64 {
+        self.line
+    }
+}
+
+/// Lifecycle of a stored transaction regarding disputes. A transaction starts `Processed`,
+/// can be `Disputed` once, and a dispute ends either `Resolved` or `ChargedBack`; both are
+/// final, so a transaction can't be disputed twice.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TxState {
+    #[default]
+    Processed,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+/// Transitions return the new state instead of changing it in place, so it can be
+/// committed only once the whole transaction has succeeded.
+impl TxState {
+    pub fn dispute(self) -> Result<Self, Error> {
+        match self {
+            TxState::Processed => Ok(TxState::Disputed),
+            _ => Err(Error::AlreadyDisputed),
+        }
+    }
+    pub fn resolve(self) -> Result<Self, Error> {
+        match self {
+            TxState::Disputed => Ok(TxState::Resolved),
+            _ => Err(Error::NotUnderDispute),
+        }
+    }
+    pub fn chargeback(self) -> Result<Self, Error> {
+        match self {
+            TxState::Disputed => Ok(TxState::ChargedBack),
+            _ => Err(Error::NotUnderDispute),
+        }
+    }
+}
+
+/// A deposit or withdrawal as kept by `Accounts` in its `TxStore`, along with its dispute
+/// state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredTx {
+    pub(crate) txtype: TxType,
+    pub(crate) client: ClientId,
+    pub(crate) amount: Amount,
+    pub(crate) state: TxState,
+    pub(crate) timestamp: Option<Timestamp>,
+}
+
+impl StoredTx {
+    /// A newly `Processed` transaction
+    pub fn new(txtype: TxType, client: ClientId, amount: Amount) -> Self {
+        Self {
+            txtype,
+            client,
+            amount,
+            state: TxState::Processed,
+            timestamp: None,
+        }
+    }
+    pub fn with_state(mut self, state: TxState) -> Self {
+        self.state = state;
+        self
+    }
+    pub fn with_timestamp(mut self, timestamp: Option<Timestamp>) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+    pub fn txtype(&self) -> TxType {
+        self.txtype
+    }
+    pub fn client(&self) -> ClientId {
+        self.client
+    }
+    pub fn amount(&self) -> Amount {
+        self.amount
+    }
+    pub fn state(&self) -> TxState {
+        self.state
+    }
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
+    }
+}
+
+/// Transaction error handling. The errors only tell what went wrong, e.g.
+/// `InsufficientFunds` doesn't say which transaction tried to withdraw the funds, nor from
+/// which client account; the readers add that context with `RowError`.
 #[derive(Error, Debug, PartialEq, Eq)]
 pub enum Error {
     #[error("Duplicate transaction #{0}")]
@@ -79,11 +598,13 @@ pub enum Error {
     #[error("Transaction #{0} not found")]
     TransactionNotFound(TxId),
     #[error("Insufficient funds for operation (asked {asked} while {available} available)")]
-    InsufficientFunds { asked: Decimal, available: Decimal },
+    InsufficientFunds { asked: Amount, available: Amount },
     #[error("Account already locked")]
     AccountLocked,
     #[error("Transaction amount must be positive")]
     NegativeAmount,
+    #[error("Amount overflow")]
+    Overflow,
     #[error("Transaction amount is missing for dispute/withdrawal")]
     MissingAmount,
     #[error("Transaction amount shouldn't be there for dispute/resolve/chargeback")]
@@ -92,4 +613,171 @@ pub enum Error {
     WrongDispute,
     #[error("Attempt to dispute/resolve/chargeback on a different client account")]
     DisputeMismatch,
+    #[error("Transaction is or has already been disputed")]
+    AlreadyDisputed,
+    #[error("Transaction isn't under dispute")]
+    NotUnderDispute,
+    #[error("Administrative transaction from a non-admin source")]
+    AdminOnly,
+    #[error("Administrative transaction without an operator")]
+    MissingOperator,
+    #[error("Account isn't locked")]
+    AccountNotLocked,
+    #[error("Account is closed")]
+    AccountClosed,
+    #[error("Account still has held funds")]
+    FundsHeld,
+    #[error("Write-ahead log unavailable")]
+    WalUnavailable,
+    #[error("Transaction store failure: {0}")]
+    StoreFailure(String),
+    #[error("Transaction #{0} can't be disputed anymore")]
+    DisputeWindowExpired(TxId),
+    #[error("Malformed row: {0}")]
+    MalformedRow(String),
+    #[error("Account {0} not found")]
+    AccountNotFound(ClientId),
+}
+
+impl Error {
+    /// Stable, machine-readable code for this error; unlike the message it won't change,
+    /// so it can be relied upon by other tools.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::DuplicateTransaction(_) => "duplicate_transaction",
+            Error::TransactionNotFound(_) => "transaction_not_found",
+            Error::InsufficientFunds { .. } => "insufficient_funds",
+            Error::AccountLocked => "account_locked",
+            Error::NegativeAmount => "negative_amount",
+            Error::Overflow => "overflow",
+            Error::MissingAmount => "missing_amount",
+            Error::UnattendedforAmount => "unattended_amount",
+            Error::WrongDispute => "wrong_dispute",
+            Error::DisputeMismatch => "dispute_mismatch",
+            Error::AlreadyDisputed => "already_disputed",
+            Error::NotUnderDispute => "not_under_dispute",
+            Error::AdminOnly => "admin_only",
+            Error::MissingOperator => "missing_operator",
+            Error::AccountNotLocked => "account_not_locked",
+            Error::AccountClosed => "account_closed",
+            Error::FundsHeld => "funds_held",
+            Error::WalUnavailable => "wal_unavailable",
+            Error::StoreFailure(_) => "store_failure",
+            Error::DisputeWindowExpired(_) => "dispute_window_expired",
+            Error::MalformedRow(_) => "malformed_row",
+            Error::AccountNotFound(_) => "account_not_found",
+        }
+    }
+    /// Numeric counterpart of `code`, for tools which would rather have numbers. New errors
+    /// get new numbers, and the number of a removed error is never reused.
+    pub fn numeric_code(&self) -> u16 {
+        match self {
+            Error::DuplicateTransaction(_) => 1,
+            Error::TransactionNotFound(_) => 2,
+            Error::InsufficientFunds { .. } => 3,
+            Error::AccountLocked => 4,
+            Error::NegativeAmount => 5,
+            Error::Overflow => 6,
+            Error::MissingAmount => 7,
+            Error::UnattendedforAmount => 8,
+            Error::WrongDispute => 9,
+            Error::DisputeMismatch => 10,
+            Error::AlreadyDisputed => 11,
+            Error::NotUnderDispute => 12,
+            Error::AdminOnly => 13,
+            Error::MissingOperator => 14,
+            Error::AccountNotLocked => 15,
+            Error::AccountClosed => 16,
+            Error::FundsHeld => 17,
+            Error::WalUnavailable => 18,
+            Error::StoreFailure(_) => 19,
+            Error::DisputeWindowExpired(_) => 20,
+            Error::MalformedRow(_) => 21,
+            Error::AccountNotFound(_) => 22,
+        }
+    }
+}
+
+/// An `Error` along with the row it comes from: its position, the input it was read from
+/// and, unless the row couldn't be read at all, which transaction it was; if so, what was
+/// read instead.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RowError {
+    error: Error,
+    at: Position,
+    input: InputSource,
+    input_name: Option<String>,
+    tx: Option<(TxType, ClientId, TxId)>,
+    content: Option<String>,
 }
+
+impl RowError {
+    pub(crate) fn new(error: Error, at: Position, input: InputSource) -> Self {
+        Self {
+            error,
+            at,
+            input,
+            input_name: None,
+            tx: None,
+            content: None,
+        }
+    }
+    pub(crate) fn with_tx(mut self, txtype: TxType, client: ClientId, id: TxId) -> Self {
+        self.tx = Some((txtype, client, id));
+        self
+    }
+    pub(crate) fn with_input_name(mut self, name: Option<&str>) -> Self {
+        self.input_name = name.map(String::from);
+        self
+    }
+    pub(crate) fn with_content(mut self, content: &str) -> Self {
+        self.content = Some(content.into());
+        self
+    }
+    pub fn error(&self) -> &Error {
+        &self.error
+    }
+    pub fn line(&self) -> u64 {
+        self.at.line()
+    }
+    /// Offset of the row in its input
+    pub fn byte(&self) -> u64 {
+        self.at.byte()
+    }
+    /// Whether the input was trusted with administrative transactions
+    pub fn input(&self) -> InputSource {
+        self.input
+    }
+    /// Name of the input (usually its path), if the reader was given one
+    pub fn input_name(&self) -> Option<&str> {
+        self.input_name.as_deref()
+    }
+    pub fn txtype(&self) -> Option<TxType> {
+        self.tx.map(|(txtype, _, _)| txtype)
+    }
+    pub fn client(&self) -> Option<ClientId> {
+        self.tx.map(|(_, client, _)| client)
+    }
+    pub fn id(&self) -> Option<TxId> {
+        self.tx.map(|(_, _, id)| id)
+    }
+    /// The row as it was read, for rows which couldn't be
+    pub fn content(&self) -> Option<&str> {
+        self.content.as_deref()
+    }
+}
+
+impl std::fmt::Display for RowError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Line {}", self.at.line())?;
+        if let Some(name) = &self.input_name {
+            write!(f, " of {name}")?;
+        }
+        if let Some((txtype, client, id)) = self.tx {
+            write!(f, ", {} #{id} of client {client}", txtype.name())?;
+        }
+        write!(f, ": {}", self.error)
+    }
+}
+
+impl std::error::Error for RowError {}
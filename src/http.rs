@@ -0,0 +1,358 @@
+use crate::{
+    compute::Accounts,
+    data::{Account, ClientId, Error, InputSource, Transaction, TypedTransaction},
+    read::{json_transaction, TransactionUser},
+    store::{AccountStore, MemoryTxStore, TxStore},
+    write::{write_accounts_json, AccountOrder},
+};
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+};
+
+/// Response to a request, before it's sent
+#[derive(Debug, PartialEq)]
+pub(crate) struct Reply {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn json(status: u16, body: Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: body.to_string().into_bytes(),
+        }
+    }
+    fn error(status: u16, code: &str, message: impl std::fmt::Display) -> Self {
+        Self::json(status, error_body(code, message))
+    }
+}
+
+fn error_body(code: &str, message: impl std::fmt::Display) -> Value {
+    json!({ "error": { "code": code, "message": message.to_string() } })
+}
+
+/// HTTP status of a rejected transaction: 4xx as it's the client's transaction which can't
+/// be applied, apart from the failures of the engine itself.
+fn status(error: &Error) -> u16 {
+    match error {
+        Error::TransactionNotFound(_) | Error::AccountNotFound(_) => 404,
+        Error::AdminOnly => 403,
+        Error::MalformedRow(_) => 400,
+        Error::NegativeAmount
+        | Error::MissingAmount
+        | Error::UnattendedforAmount
+        | Error::MissingOperator => 422,
+        Error::WalUnavailable | Error::StoreFailure(_) => 503,
+        // the transaction is well-formed, but can't be applied to the account as it is
+        _ => 409,
+    }
+}
+
+/// HTTP API over `Accounts`, served by a pool of threads:
+///
+/// - `POST /transactions` applies a transaction given as a JSON object with the same fields
+///   as the CSV columns, or a batch of them as an array;
+/// - `GET /accounts/{client}` gives the account of a client as a JSON object;
+/// - `GET /accounts` gives every account, as JSON Lines (built in memory, so it takes
+///   memory in proportion to the number of accounts).
+///
+/// Accounts are behind a `RwLock`, so reads are served in parallel while transactions are
+/// applied one request at a time (a batch being applied as a whole).
+pub struct HttpServer<S = MemoryTxStore, A = BTreeMap<ClientId, Account>> {
+    server: tiny_http::Server,
+    accounts: RwLock<Accounts<S, A>>,
+    source: InputSource,
+    threads: usize,
+    stopped: AtomicBool,
+}
+
+impl<S: TxStore, A: AccountStore> HttpServer<S, A> {
+    /// Listens on `addr` (e.g. `127.0.0.1:8080`), serving `accounts` once `run`
+    pub fn bind(addr: &str, accounts: Accounts<S, A>) -> Result<Self, anyhow::Error> {
+        let server = tiny_http::Server::http(addr)
+            .map_err(|e| anyhow::anyhow!("can't listen on {addr}: {e}"))?;
+        Ok(Self {
+            server,
+            accounts: RwLock::new(accounts),
+            source: InputSource::default(),
+            threads: 1,
+            stopped: AtomicBool::new(false),
+        })
+    }
+    /// Where transactions are deemed to come from, e.g. to accept administrative ones
+    pub fn with_source(mut self, source: InputSource) -> Self {
+        self.source = source;
+        self
+    }
+    /// Number of threads serving requests (at least one)
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+    /// Address actually listened on, e.g. when binding port 0
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+    /// Gives back the accounts, once the server is stopped
+    pub fn into_accounts(self) -> Accounts<S, A> {
+        self.accounts
+            .into_inner()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    // Changes to accounts are only made once nothing can fail anymore (see `Accounts`), so
+    // a panicking thread can't have left them half-updated.
+    fn read(&self) -> RwLockReadGuard<'_, Accounts<S, A>> {
+        self.accounts
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    fn write(&self) -> RwLockWriteGuard<'_, Accounts<S, A>> {
+        self.accounts
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    fn typed(&self, tx: Transaction) -> Result<TypedTransaction, Error> {
+        self.source.check(&tx)?;
+        TypedTransaction::try_from(tx)
+    }
+    fn post_transactions(&self, body: &[u8]) -> Reply {
+        let value: Value = match serde_json::from_slice(body) {
+            Ok(value) => value,
+            Err(e) => return Reply::error(400, "malformed_request", e),
+        };
+        let batch = value.is_array();
+        let txs = match value {
+            Value::Array(values) => values.iter().map(json_transaction).collect(),
+            value => json_transaction(&value).map(|tx| vec![tx]),
+        };
+        let txs: Vec<Transaction> = match txs {
+            Ok(txs) => txs,
+            Err(e) => return Reply::error(400, "malformed_request", e),
+        };
+        let mut accounts = self.write();
+        let mut outcomes = txs.into_iter().map(|tx| {
+            let (id, timestamp) = (tx.id(), tx.timestamp());
+            let result = self
+                .typed(tx)
+                .and_then(|tx| accounts.use_tx_at(tx, timestamp));
+            (id, result)
+        });
+        if !batch {
+            return match outcomes.next() {
+                Some((id, Ok(()))) => Reply::json(200, json!({ "tx": id, "status": "accepted" })),
+                Some((_, Err(e))) => Reply::error(status(&e), e.code(), &e),
+                None => unreachable!("a single transaction was given"),
+            };
+        }
+        let outcomes = outcomes
+            .map(|(id, result)| match result {
+                Ok(()) => json!({ "tx": id, "status": "accepted" }),
+                Err(e) => {
+                    let mut outcome = error_body(e.code(), &e);
+                    outcome["tx"] = id.into();
+                    outcome["status"] = "rejected".into();
+                    outcome
+                }
+            })
+            .collect();
+        Reply::json(200, Value::Array(outcomes))
+    }
+    /// The whole body is built in memory, which grows with the number of accounts: streaming
+    /// it would hold the lock on the accounts for as long as the client takes to read it,
+    /// and a slow client would then stop every transaction.
+    fn get_accounts(&self) -> Reply {
+        let mut body = Vec::new();
+        if let Err(e) = write_accounts_json(&mut body, &*self.read(), AccountOrder::Client) {
+            return Reply::error(503, "store_failure", e);
+        }
+        Reply {
+            status: 200,
+            content_type: "application/x-ndjson",
+            body,
+        }
+    }
+    fn get_account(&self, client: &str) -> Reply {
+        let Ok(client) = client.parse::<ClientId>() else {
+            return Reply::error(400, "invalid_client", format!("Invalid client id {client}"));
+        };
+        match self.read().account_store().get(client) {
+            Ok(Some(account)) => Reply {
+                status: 200,
+                content_type: "application/json",
+                body: serde_json::to_vec(&account).expect("accounts serialize to JSON"),
+            },
+            Ok(None) => Reply::error(404, "account_not_found", format!("No account {client}")),
+            Err(e) => Reply::error(status(&e), e.code(), &e),
+        }
+    }
+    pub(crate) fn route(&self, method: &str, url: &str, body: &[u8]) -> Reply {
+        let path = url.split('?').next().unwrap_or_default();
+        match (method, path) {
+            ("POST", "/transactions") => self.post_transactions(body),
+            ("GET", "/accounts") => self.get_accounts(),
+            ("GET", path) if path.starts_with("/accounts/") => {
+                self.get_account(&path["/accounts/".len()..])
+            }
+            _ => Reply::error(404, "not_found", format!("No route for {method} {path}")),
+        }
+    }
+    fn handle(&self, mut request: tiny_http::Request) {
+        let mut body = Vec::new();
+        let reply = match request.as_reader().read_to_end(&mut body) {
+            Ok(_) => self.route(request.method().as_str(), request.url(), &body),
+            Err(e) => Reply::error(400, "malformed_request", e),
+        };
+        let content_type = tiny_http::Header::from_bytes("Content-Type", reply.content_type)
+            .expect("content types are valid headers");
+        let response = tiny_http::Response::from_data(reply.body)
+            .with_status_code(reply.status)
+            .with_header(content_type);
+        if let Err(e) = request.respond(response) {
+            eprintln!("Can't respond to a request: {e}");
+        }
+    }
+    /// Serves requests until `stop` is called
+    pub fn run(&self)
+    where
+        S: Send + Sync,
+        A: Send + Sync,
+    {
+        std::thread::scope(|scope| {
+            for _ in 0..self.threads {
+                scope.spawn(|| loop {
+                    match self.server.recv() {
+                        Ok(request) => self.handle(request),
+                        Err(_) if self.stopped.load(Ordering::Acquire) => break,
+                        Err(e) => eprintln!("Can't receive a request: {e}"),
+                    }
+                });
+            }
+        });
+    }
+    /// Makes `run` return, once the requests being handled are done
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Release);
+        for _ in 0..self.threads {
+            self.server.unblock();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HttpServer, Reply};
+    use crate::{
+        compute::Accounts,
+        data::{amount, InputSource},
+    };
+    use serde_json::{json, Value};
+    use std::io::{Read, Write};
+
+    fn server() -> HttpServer {
+        HttpServer::bind("127.0.0.1:0", Accounts::new()).unwrap()
+    }
+    fn json(reply: &Reply) -> Value {
+        serde_json::from_slice(&reply.body).unwrap()
+    }
+
+    #[test]
+    fn http_transactions() {
+        let server = server();
+        let reply = server.route(
+            "POST",
+            "/transactions",
+            br#"{"type": "deposit", "client": 1, "tx": 1, "amount": "2.5"}"#,
+        );
+        assert_eq!(reply.status, 200);
+        assert_eq!(json(&reply), json!({"tx": 1, "status": "accepted"}));
+        let reply = server.route(
+            "POST",
+            "/transactions",
+            br#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": "3"}"#,
+        );
+        assert_eq!(reply.status, 409);
+        assert_eq!(json(&reply)["error"]["code"], "insufficient_funds");
+        let reply = server.route(
+            "POST",
+            "/transactions",
+            br#"[
+                {"type": "withdrawal", "client": 1, "tx": 2, "amount": "1"},
+                {"type": "dispute", "client": 1, "tx": 7},
+                {"type": "lock", "client": 1, "tx": 3, "operator": "ops"}
+            ]"#,
+        );
+        assert_eq!(reply.status, 200);
+        let outcomes = json(&reply);
+        assert_eq!(outcomes[0], json!({"tx": 2, "status": "accepted"}));
+        assert_eq!(outcomes[1]["status"], "rejected");
+        assert_eq!(outcomes[1]["error"]["code"], "transaction_not_found");
+        assert_eq!(outcomes[2]["error"]["code"], "admin_only");
+        let reply = server.route("POST", "/transactions", br#"{"type": "deposit"}"#);
+        assert_eq!(reply.status, 400);
+        assert_eq!(json(&reply)["error"]["code"], "malformed_request");
+        let accounts = server.into_accounts();
+        assert_eq!(accounts.account(1).unwrap().available(), amount!(1.5));
+    }
+    #[test]
+    fn http_accounts() {
+        let server = server().with_source(InputSource::Admin);
+        server.route(
+            "POST",
+            "/transactions",
+            br#"[
+                {"type": "deposit", "client": 2, "tx": 1, "amount": "1"},
+                {"type": "deposit", "client": 1, "tx": 2, "amount": "2"},
+                {"type": "lock", "client": 1, "tx": 3, "operator": "ops"}
+            ]"#,
+        );
+        let reply = server.route("GET", "/accounts/1", b"");
+        assert_eq!(reply.status, 200);
+        assert_eq!(
+            json(&reply),
+            json!({"client": 1, "available": "2.0000", "held": "0.0000", "total": "2.0000", "locked": true})
+        );
+        assert_eq!(server.route("GET", "/accounts/3", b"").status, 404);
+        assert_eq!(server.route("GET", "/accounts/client", b"").status, 400);
+        assert_eq!(server.route("DELETE", "/accounts/1", b"").status, 404);
+        let reply = server.route("GET", "/accounts?pretty", b"");
+        assert_eq!(reply.content_type, "application/x-ndjson");
+        let lines = String::from_utf8(reply.body).unwrap();
+        let clients = lines
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap()["client"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(clients, [1, 2]);
+    }
+    #[test]
+    fn http_server() {
+        let server = server().with_threads(2);
+        let addr = server.local_addr().unwrap();
+        std::thread::scope(|scope| {
+            scope.spawn(|| server.run());
+            let body = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1"}"#;
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            write!(
+                stream,
+                "POST /transactions HTTP/1.1\r\nHost: {addr}\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+            assert!(response.ends_with(r#"{"status":"accepted","tx":1}"#));
+            server.stop();
+        });
+        assert!(server.into_accounts().account(1).is_some());
+    }
+}
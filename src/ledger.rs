@@ -0,0 +1,179 @@
+use crate::data::{Account, Amount, ClientId, TxId, TxType};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+/// Immutable record of a change made to an account by an accepted transaction: what
+/// changed, and the state it resulted in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LedgerEvent {
+    sequence: u64,
+    tx: TxId,
+    client: ClientId,
+    #[serde(rename = "type")]
+    txtype: TxType,
+    available_delta: Decimal,
+    held_delta: Decimal,
+    available: Amount,
+    held: Amount,
+    locked: bool,
+    closed: bool,
+}
+
+impl LedgerEvent {
+    /// Position of the event in the journal, starting at 1
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+    pub fn tx(&self) -> TxId {
+        self.tx
+    }
+    pub fn client(&self) -> ClientId {
+        self.client
+    }
+    pub fn txtype(&self) -> TxType {
+        self.txtype
+    }
+    pub fn available_delta(&self) -> Decimal {
+        self.available_delta
+    }
+    pub fn held_delta(&self) -> Decimal {
+        self.held_delta
+    }
+    /// State of the account right after the event
+    pub fn account(&self) -> Account {
+        Account {
+            client: self.client,
+            available: self.available,
+            held: self.held,
+            locked: self.locked,
+            closed: self.closed,
+        }
+    }
+}
+
+/// Append-only journal of every change made to the accounts, in order. Since each event
+/// carries the resulting state, any account can be rebuilt as of any point in time
+/// without replaying anything.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ledger {
+    events: Vec<LedgerEvent>,
+}
+
+impl Ledger {
+    /// Appends an event for a transaction which made `before` into `after`, unless it
+    /// changed nothing (e.g. a deposit of 0).
+    pub(crate) fn record(&mut self, tx: TxId, txtype: TxType, before: &Account, after: &Account) {
+        if before == after {
+            return;
+        }
+        self.events.push(LedgerEvent {
+            sequence: self.events.len() as u64 + 1,
+            tx,
+            client: after.client,
+            txtype,
+            available_delta: Decimal::from(after.available) - Decimal::from(before.available),
+            held_delta: Decimal::from(after.held) - Decimal::from(before.held),
+            available: after.available,
+            held: after.held,
+            locked: after.locked,
+            closed: after.closed,
+        });
+    }
+    /// All the events, in sequence order
+    pub fn events(&self) -> &[LedgerEvent] {
+        &self.events
+    }
+    /// Sequence number of the last event, 0 if there's none
+    pub fn last_sequence(&self) -> u64 {
+        self.events.len() as u64
+    }
+    /// Events of a client, in sequence order
+    pub fn history(&self, client: ClientId) -> impl Iterator<Item = &LedgerEvent> {
+        self.events.iter().filter(move |e| e.client == client)
+    }
+    /// State of a client's account as of `sequence` (i.e. once the events up to and
+    /// including it were applied), if it existed by then.
+    pub fn account_at(&self, client: ClientId, sequence: u64) -> Option<Account> {
+        let end = self.events.partition_point(|e| e.sequence <= sequence);
+        self.events[..end]
+            .iter()
+            .rev()
+            .find(|e| e.client == client)
+            .map(LedgerEvent::account)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compute::Accounts,
+        data::{amount, Account, InputSource, TxType},
+        read::{read_transactions, ReadOptions},
+        testing::TRANSACTIONS_CSV,
+    };
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn ledger_events() {
+        let mut accounts = Accounts::new();
+        read_transactions(
+            TRANSACTIONS_CSV,
+            InputSource::User,
+            &mut accounts,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        assert!(accounts.ledger().is_none());
+
+        let mut accounts = Accounts::new().with_ledger();
+        read_transactions(
+            TRANSACTIONS_CSV,
+            InputSource::User,
+            &mut accounts,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        let ledger = accounts.ledger().unwrap();
+        // the rejected withdrawal isn't recorded
+        assert_eq!(ledger.last_sequence(), 5);
+        let history = ledger.history(1).collect::<Vec<_>>();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[1].sequence(), 3);
+        assert_eq!(history[1].txtype(), TxType::Dispute);
+        assert_eq!(history[1].available_delta(), dec!(-1));
+        assert_eq!(history[1].held_delta(), dec!(1));
+        assert_eq!(history[2].tx(), 1);
+        assert!(history[2].account().locked());
+        for account in accounts.accounts() {
+            let client = account.client();
+            assert_eq!(ledger.account_at(client, 5).as_ref(), Some(account));
+        }
+    }
+    #[test]
+    fn ledger_account_at() {
+        let mut accounts = Accounts::new().with_ledger();
+        read_transactions(
+            TRANSACTIONS_CSV,
+            InputSource::User,
+            &mut accounts,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        let ledger = accounts.ledger().unwrap();
+        assert_eq!(ledger.account_at(2, 1), None);
+        let at = |sequence| ledger.account_at(1, sequence).unwrap();
+        assert_eq!(at(2), at(1));
+        assert_eq!(
+            at(3),
+            Account {
+                client: 1,
+                available: amount!(0),
+                held: amount!(1),
+                locked: false,
+                closed: false,
+            }
+        );
+        assert_eq!(at(4), at(3));
+        assert_eq!(ledger.account_at(2, 4).unwrap().available(), amount!(0.5));
+    }
+}
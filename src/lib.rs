@@ -0,0 +1,70 @@
+//! Toy payments engine: transactions (deposits, withdrawals, disputes...) are read from CSV
+//! or JSON Lines, applied to client accounts, and the resulting accounts are written back.
+//!
+//! The main entry points are `Accounts`, which holds the state of every account and applies
+//! transactions to them through the `TransactionUser` trait, and the `read_*`/`write_*`
+//! functions to feed it from a file and export its accounts.
+//!
+//! ```
+//! use payments::{Accounts, TransactionUser, TypedTransaction};
+//! use rust_decimal_macros::dec;
+//!
+//! let mut accounts = Accounts::new();
+//! accounts
+//!     .use_tx(TypedTransaction::Deposit {
+//!         client: 1,
+//!         id: 1,
+//!         amount: payments::Amount::new(dec!(1.5)).unwrap(),
+//!     })
+//!     .unwrap();
+//! assert_eq!(accounts.account(1).unwrap().total(), dec!(1.5));
+//! ```
+
+#[cfg(feature = "async")]
+mod async_read;
+mod compute;
+mod data;
+#[cfg(feature = "http")]
+mod http;
+mod ledger;
+mod read;
+mod shard;
+mod snapshot;
+mod socket;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod statement;
+mod store;
+#[cfg(test)]
+mod testing;
+mod wal;
+mod write;
+
+#[cfg(feature = "async")]
+pub use async_read::{
+    read_transactions_async, read_transactions_stream, AsyncAdapter, AsyncTransactionUser,
+};
+pub use compute::{Accounts, DisputePolicy};
+pub use data::{
+    Account, AdminInfo, Amount, ClientId, Error, Format, InputSource, OperatorId, Position,
+    RowError, StoredTx, Timestamp, Transaction, TxId, TxState, TxType, TypedTransaction,
+    SIGNIFICANT_DIGITS,
+};
+#[cfg(feature = "http")]
+pub use http::HttpServer;
+pub use ledger::{Ledger, LedgerEvent};
+pub use read::{
+    read_transactions, read_transactions_json, resume_transactions, resume_transactions_json,
+    ErrorPolicy, ReadOptions, ReadReport, RejectSink, TransactionUser,
+};
+pub use shard::ShardedAccounts;
+pub use snapshot::{SharedRejects, Snapshotter};
+pub use socket::LineServer;
+#[cfg(feature = "sqlite")]
+pub use sqlite::{write_accounts_sqlite, SqliteAccountStore, SqliteTransactionLog};
+pub use statement::Statement;
+pub use store::{AccountStore, DiskTxStore, MemoryTxStore, TxStore};
+pub use wal::Wal;
+pub use write::{
+    write_accounts, write_accounts_json, write_ledger, write_statement, AccountOrder, RejectWriter,
+};
@@ -1,19 +1,515 @@
-use compute::Accounts;
-use read::read_transactions;
-use write::write_accounts;
+#[cfg(feature = "http")]
+use payments::HttpServer;
+use payments::{
+    read_transactions, read_transactions_json, resume_transactions, resume_transactions_json,
+    write_accounts, write_accounts_json, write_ledger, write_statement, AccountOrder, AccountStore,
+    Accounts, ClientId, DiskTxStore, DisputePolicy, ErrorPolicy, Format, InputSource, LineServer,
+    MemoryTxStore, Position, ReadOptions, RejectWriter, ShardedAccounts, Snapshotter, Statement,
+    Timestamp, TransactionUser, TxStore, Wal,
+};
+#[cfg(feature = "sqlite")]
+use payments::{write_accounts_sqlite, SqliteAccountStore, SqliteTransactionLog};
+use std::sync::{Arc, Mutex};
 
-mod compute;
-mod data;
-mod read;
-mod write;
+/// Default number of input rows between two snapshots
+const SNAPSHOT_EVERY: u64 = 100_000;
+/// Default number of transactions between two syncs of the write-ahead log
+const WAL_BATCH: usize = 1_000;
+/// Default address of the HTTP API
+const LISTEN: &str = "127.0.0.1:8080";
+/// Default address of the line protocol
+const INGEST_LISTEN: &str = "127.0.0.1:9000";
 
-fn main() -> Result<(), anyhow::Error> {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 {
-        anyhow::bail!("usage: {} transactions.csv > accounts.csv", args[0]);
+/// Reads `file` in the given format, from its beginning or from `start`; failed rows are
+/// reported on stderr or in the rejects along the way, so only the report's summary is
+/// printed, on stderr
+fn read_input(
+    file: std::fs::File,
+    format: Format,
+    start: Option<Position>,
+    source: InputSource,
+    mut user: &mut dyn TransactionUser,
+    options: ReadOptions,
+) -> Result<(), anyhow::Error> {
+    let user = &mut user;
+    let report = match (format, start) {
+        (Format::Csv, None) => read_transactions(file, source, user, options),
+        (Format::Csv, Some(start)) => resume_transactions(file, start, source, user, options),
+        (Format::JsonLines, None) => read_transactions_json(file, source, user, options),
+        (Format::JsonLines, Some(start)) => {
+            resume_transactions_json(file, start, source, user, options)
+        }
+    };
+    if let Some(summary) = report?.summary() {
+        eprintln!("{summary}");
     }
-    let mut accounts = Accounts::new();
-    read_transactions(std::fs::File::open(&args[1])?, &mut accounts)?;
-    write_accounts(std::io::stdout(), &accounts)?;
     Ok(())
 }
+
+/// Runs `read` on `user`, through a write-ahead log if any; `read` is then given the
+/// position of the input where the log left it
+fn with_wal<U: TransactionUser>(
+    mut user: U,
+    wal: Option<(String, usize)>,
+    read: impl FnOnce(&mut dyn TransactionUser, Option<Position>) -> Result<(), anyhow::Error>,
+) -> Result<(), anyhow::Error> {
+    match wal {
+        Some((path, batch)) => {
+            let mut wal = Wal::open(path.as_ref(), user, batch)?;
+            if wal.replayed() > 0 && wal.position().is_none() {
+                anyhow::bail!("{path} has no input position, the input can't be read on top of it");
+            }
+            let start = wal.position();
+            read(&mut wal, start)?;
+            wal.finish()?;
+            Ok(())
+        }
+        None => read(&mut user, None),
+    }
+}
+
+/// Writes the ledger and the database if asked for, and the accounts to stdout
+fn write_output<S: TxStore, A: AccountStore>(
+    accounts: &Accounts<S, A>,
+    ledger: Option<String>,
+    database: Option<String>,
+    format: Format,
+    order: AccountOrder,
+) -> Result<(), anyhow::Error> {
+    if let Some(path) = database {
+        #[cfg(feature = "sqlite")]
+        write_accounts_sqlite(path.as_ref(), accounts)?;
+        #[cfg(not(feature = "sqlite"))]
+        anyhow::bail!("can't write {path} without the sqlite feature");
+    }
+    if let (Some(path), Some(journal)) = (ledger, accounts.ledger()) {
+        let format = Format::from_path(path.as_ref());
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        write_ledger(file, journal, format)?;
+    }
+    match format {
+        Format::Csv => write_accounts(std::io::stdout(), accounts, order),
+        Format::JsonLines => write_accounts_json(std::io::stdout(), accounts, order),
+    }
+}
+
+/// Reads the value following an option
+fn value<T: std::str::FromStr>(args: &mut impl Iterator<Item = String>) -> Option<T> {
+    args.next()?.parse().ok()
+}
+
+/// Options of every subcommand, setting up the accounts
+#[derive(Default)]
+struct EngineOptions {
+    policy: DisputePolicy,
+    window: Option<Timestamp>,
+    source: InputSource,
+}
+
+impl EngineOptions {
+    /// Takes `arg` (and its value) if it's one of these options, telling whether it was
+    fn parse(&mut self, arg: &str, args: &mut impl Iterator<Item = String>) -> Option<bool> {
+        match arg {
+            "--dispute-withdrawals" => self.policy = DisputePolicy::DepositsAndWithdrawals,
+            "--dispute-window" => self.window = Some(value(args)?),
+            "--admin" => self.source = InputSource::Admin,
+            _ => return Some(false),
+        }
+        Some(true)
+    }
+    /// Whether the dispute options were given
+    fn disputes(&self) -> bool {
+        self.policy != DisputePolicy::default() || self.window.is_some()
+    }
+    /// Sets the dispute options on new `accounts`
+    fn accounts<S: TxStore, A: AccountStore>(&self, accounts: Accounts<S, A>) -> Accounts<S, A> {
+        let accounts = accounts.with_dispute_policy(self.policy);
+        match self.window {
+            Some(window) => accounts.with_dispute_window(window),
+            None => accounts,
+        }
+    }
+}
+
+/// Options of `serve`
+#[derive(Default)]
+struct ServeOptions {
+    listen: Option<String>,
+    threads: Option<usize>,
+}
+
+impl ServeOptions {
+    fn parse(mut args: impl Iterator<Item = String>) -> Option<(Self, EngineOptions)> {
+        let (mut options, mut engine) = (Self::default(), EngineOptions::default());
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--listen" => options.listen = Some(value(&mut args)?),
+                "--threads" => options.threads = Some(value(&mut args)?),
+                arg if engine.parse(arg, &mut args)? => {}
+                _ => return None,
+            }
+        }
+        Some((options, engine))
+    }
+}
+
+/// Options of `ingest`
+#[derive(Default)]
+struct IngestOptions {
+    listen: Option<String>,
+    #[cfg(unix)]
+    unix_socket: Option<String>,
+}
+
+impl IngestOptions {
+    fn parse(mut args: impl Iterator<Item = String>) -> Option<(Self, EngineOptions)> {
+        let (mut options, mut engine) = (Self::default(), EngineOptions::default());
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--listen" => options.listen = Some(value(&mut args)?),
+                #[cfg(unix)]
+                "--unix" => options.unix_socket = Some(value(&mut args)?),
+                arg if engine.parse(arg, &mut args)? => {}
+                _ => return None,
+            }
+        }
+        Some((options, engine))
+    }
+}
+
+/// Options of the batch runs, reading an input file: the default one and `statement`
+#[derive(Default)]
+struct BatchOptions {
+    input: String,
+    statement: bool,
+    client: Option<ClientId>,
+    rejects: Option<String>,
+    on_error: ErrorPolicy,
+    order: AccountOrder,
+    input_format: Option<Format>,
+    output_format: Format,
+    snapshot: Option<String>,
+    snapshot_every: Option<u64>,
+    resume_from: Option<String>,
+    wal: Option<String>,
+    wal_batch: Option<usize>,
+    ledger: Option<String>,
+    threads: Option<usize>,
+    tx_store: Option<String>,
+    account_store: Option<String>,
+    database: Option<String>,
+    database_transactions: bool,
+}
+
+impl BatchOptions {
+    fn parse(
+        mut args: impl Iterator<Item = String>,
+        statement: bool,
+    ) -> Option<(Self, EngineOptions)> {
+        let mut options = Self {
+            statement,
+            ..Self::default()
+        };
+        let mut engine = EngineOptions::default();
+        let mut input = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--client" if statement => options.client = Some(value(&mut args)?),
+                "--rejects" => options.rejects = Some(value(&mut args)?),
+                "--on-error" => options.on_error = ErrorPolicy::from_name(&args.next()?)?,
+                "--input-format" => options.input_format = Some(Format::from_name(&args.next()?)?),
+                "--output-format" => options.output_format = Format::from_name(&args.next()?)?,
+                "--snapshot" => options.snapshot = Some(value(&mut args)?),
+                "--snapshot-every" => options.snapshot_every = Some(value(&mut args)?),
+                "--resume-from" => options.resume_from = Some(value(&mut args)?),
+                "--wal" => options.wal = Some(value(&mut args)?),
+                "--wal-batch" => options.wal_batch = Some(value(&mut args)?),
+                "--ledger" => options.ledger = Some(value(&mut args)?),
+                "--threads" => options.threads = Some(value(&mut args)?),
+                "--tx-store" => options.tx_store = Some(value(&mut args)?),
+                "--account-store" => options.account_store = Some(value(&mut args)?),
+                "--database" => options.database = Some(value(&mut args)?),
+                "--database-transactions" => options.database_transactions = true,
+                "--sort-by" => {
+                    options.order = match args.next()?.as_str() {
+                        "client" => AccountOrder::Client,
+                        "total" => AccountOrder::Total,
+                        "locked" => AccountOrder::Locked,
+                        _ => return None,
+                    }
+                }
+                arg if engine.parse(arg, &mut args)? => {}
+                _ if input.is_none() && !arg.starts_with("--") => input = Some(arg),
+                _ => return None,
+            }
+        }
+        if options.database_transactions && options.database.is_none() {
+            return None;
+        }
+        options.input = input?;
+        Some((options, engine))
+    }
+}
+
+/// Opens the rejects file; when resuming, the rejects of the rows read before are kept, up
+/// to `length` if the snapshot recorded it (the later ones will be written again)
+fn open_rejects(
+    path: &str,
+    resuming: bool,
+    length: Option<u64>,
+) -> Result<RejectWriter<std::io::BufWriter<std::fs::File>>, anyhow::Error> {
+    let format = Format::from_path(path.as_ref());
+    if !resuming {
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        return Ok(RejectWriter::new(file, format));
+    }
+    let file = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)?;
+    if let Some(length) = length.filter(|&length| length < file.metadata().map_or(0, |m| m.len())) {
+        file.set_len(length)?;
+    }
+    let empty = file.metadata()?.len() == 0;
+    let file = std::io::BufWriter::new(file);
+    Ok(if empty {
+        RejectWriter::new(file, format)
+    } else {
+        RejectWriter::append(file, format)
+    })
+}
+
+fn serve(options: ServeOptions, engine: EngineOptions) -> Result<(), anyhow::Error> {
+    let listen = options.listen.as_deref().unwrap_or(LISTEN);
+    #[cfg(feature = "http")]
+    {
+        let server = HttpServer::bind(listen, engine.accounts(Accounts::new()))?
+            .with_source(engine.source)
+            .with_threads(options.threads.unwrap_or(1));
+        eprintln!("Listening on http://{listen}");
+        server.run();
+        Ok(())
+    }
+    #[cfg(not(feature = "http"))]
+    {
+        let _ = (options.threads, engine);
+        anyhow::bail!("serve needs the http feature, can't listen on {listen}");
+    }
+}
+
+fn ingest(options: IngestOptions, engine: EngineOptions) -> Result<(), anyhow::Error> {
+    let server = LineServer::new(engine.accounts(Accounts::new())).with_source(engine.source);
+    #[cfg(unix)]
+    if let Some(path) = options.unix_socket {
+        let listener = std::os::unix::net::UnixListener::bind(&path)?;
+        eprintln!("Listening on {path}");
+        return server.run_unix(listener);
+    }
+    let listen = options.listen.as_deref().unwrap_or(INGEST_LISTEN);
+    let listener = std::net::TcpListener::bind(listen)?;
+    eprintln!("Listening on {listen}");
+    server.run_tcp(listener)
+}
+
+fn batch(options: BatchOptions, engine: EngineOptions) -> Result<(), anyhow::Error> {
+    let BatchOptions {
+        input,
+        statement,
+        client,
+        rejects,
+        on_error,
+        order,
+        input_format,
+        output_format,
+        snapshot,
+        snapshot_every,
+        resume_from,
+        wal,
+        wal_batch,
+        ledger,
+        threads,
+        tx_store,
+        account_store,
+        database,
+        database_transactions,
+    } = options;
+    if cfg!(not(feature = "sqlite")) && (account_store.is_some() || database.is_some()) {
+        anyhow::bail!("--account-store and --database need the sqlite feature");
+    }
+    if wal.is_some() && resume_from.is_some() {
+        // the log already holds everything from the beginning
+        anyhow::bail!("--wal and --resume-from can't be used together");
+    }
+    if resume_from.is_some() && engine.disputes() {
+        // they'd be silently replaced by the ones from the snapshot
+        anyhow::bail!(
+            "--dispute-withdrawals and --dispute-window can't be used with --resume-from, \
+             the snapshot has its own"
+        );
+    }
+    if statement && snapshot.is_some() {
+        anyhow::bail!("statement can't take snapshots");
+    }
+    if statement && database.is_some() {
+        anyhow::bail!("statement can't write a database");
+    }
+    // the shards only know about their own clients and report failures themselves
+    let threads = threads.unwrap_or(1);
+    let sharded = threads > 1;
+    if sharded
+        && (statement
+            || rejects.is_some()
+            || on_error != ErrorPolicy::Continue
+            || snapshot.is_some()
+            || resume_from.is_some()
+            || wal.is_some()
+            || ledger.is_some()
+            || database_transactions)
+    {
+        anyhow::bail!(
+            "--threads can't be used with statement, --rejects, --on-error, snapshots, --wal, \
+             --ledger or --database-transactions"
+        );
+    }
+    // snapshots only know about transactions and accounts kept in memory
+    let stores = tx_store.is_some() || account_store.is_some();
+    if stores && (statement || sharded || snapshot.is_some() || resume_from.is_some()) {
+        anyhow::bail!(
+            "--tx-store and --account-store can't be used with statement, --threads or snapshots"
+        );
+    }
+    let wal = wal.map(|path| (path, wal_batch.unwrap_or(WAL_BATCH)));
+    let input_format = input_format.unwrap_or_else(|| Format::from_path(input.as_ref()));
+    // a write-ahead log which isn't empty is resumed too
+    let resuming = resume_from.is_some()
+        || wal
+            .as_ref()
+            .is_some_and(|(path, _)| std::fs::metadata(path).is_ok_and(|m| m.len() > 0));
+    // when resuming, the dispute options are the ones from the snapshot
+    let (mut accounts, start, rejected) = match resume_from {
+        Some(path) => {
+            let file = std::io::BufReader::new(std::fs::File::open(path)?);
+            let (accounts, position, rejected) = Accounts::load_snapshot(file)?;
+            (accounts, Some(position), rejected)
+        }
+        None => (engine.accounts(Accounts::new()), None, None),
+    };
+    let rejects = rejects
+        .map(|path| open_rejects(&path, resuming, rejected))
+        .transpose()?
+        .map(|rejects| Arc::new(Mutex::new(rejects)));
+    let flush_rejects = || match &rejects {
+        Some(rejects) => rejects
+            .lock()
+            .map_err(|_| anyhow::anyhow!("rejects poisoned"))?
+            .flush(),
+        None => Ok(()),
+    };
+    if ledger.is_some() {
+        accounts = accounts.with_ledger();
+    }
+    let file = std::fs::File::open(&input)?;
+    let mut options = ReadOptions::default()
+        .with_policy(on_error)
+        .with_name(&input);
+    let mut sink = rejects.clone();
+    if let Some(sink) = &mut sink {
+        options = options.with_rejects(sink);
+    }
+    let source = engine.source;
+    let read = |user: &mut dyn TransactionUser, logged: Option<Position>| {
+        let start = logged.or(start);
+        #[cfg(feature = "sqlite")]
+        if let (Some(path), true) = (&database, database_transactions) {
+            let mut log = SqliteTransactionLog::create(path.as_ref(), user)?;
+            let read = read_input(file, input_format, start, source, &mut log, options);
+            // what was logged before stopping is kept
+            log.finish()?;
+            return read;
+        }
+        read_input(file, input_format, start, source, user, options)
+    };
+    if statement {
+        let mut statement = Statement::new(&mut accounts, client);
+        with_wal(&mut statement, wal, read)?;
+        flush_rejects()?;
+        write_statement(std::io::stdout(), &statement, output_format)?;
+        return Ok(());
+    }
+    if stores {
+        let txset: Box<dyn TxStore> = match tx_store {
+            Some(path) => Box::new(DiskTxStore::create(path.as_ref())?),
+            None => Box::new(MemoryTxStore::default()),
+        };
+        let account_store: Box<dyn AccountStore> = match account_store {
+            #[cfg(feature = "sqlite")]
+            Some(path) => Box::new(SqliteAccountStore::create(path.as_ref())?),
+            #[cfg(not(feature = "sqlite"))]
+            Some(_) => anyhow::bail!("--account-store needs the sqlite feature"),
+            None => Box::new(std::collections::BTreeMap::new()),
+        };
+        let mut accounts = engine.accounts(Accounts::with_stores(txset, account_store));
+        if ledger.is_some() {
+            accounts = accounts.with_ledger();
+        }
+        with_wal(&mut accounts, wal, read)?;
+        flush_rejects()?;
+        return write_output(&accounts, ledger, database, output_format, order);
+    }
+    match snapshot {
+        _ if sharded => {
+            let mut shards = ShardedAccounts::new(threads, engine.policy, engine.window);
+            read(&mut shards, None)?;
+            accounts = shards.finish()?;
+        }
+        Some(path) => {
+            let every = snapshot_every.unwrap_or(SNAPSHOT_EVERY);
+            let mut snapshotter = Snapshotter::new(&mut accounts, path, every);
+            if let Some(rejects) = &rejects {
+                snapshotter = snapshotter.with_rejects(rejects.clone());
+            }
+            with_wal(&mut snapshotter, wal, read)?;
+            snapshotter.finish()?;
+        }
+        None => with_wal(&mut accounts, wal, read)?,
+    }
+    flush_rejects()?;
+    write_output(&accounts, ledger, database, output_format, order)
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    let mut args = std::env::args().peekable();
+    let program = args.next().unwrap_or_default();
+    let usage = || {
+        anyhow::anyhow!(
+            "usage: {program} [statement [--client id]] [--dispute-withdrawals] \
+             [--dispute-window seconds] [--admin] [--rejects rejects.csv] \
+             [--on-error continue|abort|abort-after-N|skip-malformed-rows] \
+             [--sort-by client|total|locked] [--input-format csv|jsonl] [--output-format csv|jsonl] \
+             [--snapshot snapshot.json [--snapshot-every rows]] [--resume-from snapshot.json] \
+             [--wal wal.jsonl [--wal-batch transactions]] [--ledger ledger.csv] \
+             [--threads shards] [--tx-store transactions.db] [--account-store accounts.db] \
+             [--database results.db [--database-transactions]] transactions.csv > accounts.csv\n\
+             or:    {program} serve [--listen address] [--threads threads] [--admin] \
+             [--dispute-withdrawals] [--dispute-window seconds]\n\
+             or:    {program} ingest [--listen address | --unix socket] [--admin] \
+             [--dispute-withdrawals] [--dispute-window seconds]"
+        )
+    };
+    let subcommand = args.next_if(|arg| matches!(arg.as_str(), "statement" | "serve" | "ingest"));
+    match subcommand.as_deref() {
+        Some("serve") => {
+            let (options, engine) = ServeOptions::parse(args).ok_or_else(usage)?;
+            serve(options, engine)
+        }
+        Some("ingest") => {
+            let (options, engine) = IngestOptions::parse(args).ok_or_else(usage)?;
+            ingest(options, engine)
+        }
+        subcommand => {
+            let statement = subcommand == Some("statement");
+            let (options, engine) = BatchOptions::parse(args, statement).ok_or_else(usage)?;
+            batch(options, engine)
+        }
+    }
+}
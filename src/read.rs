@@ -1,54 +1,612 @@
-use crate::data::{Error, Transaction, SIGNIFICANT_DIGITS};
+use crate::data::{
+    Error, InputSource, Position, RowError, Timestamp, Transaction, TypedTransaction,
+};
+use std::io::{BufRead, Seek};
 
-/// Trait for doing something with a `Transaction` read from a CSV file
+/// Trait for doing something with a `TypedTransaction` read from a CSV file
 /// (or received from elsewhere). Used by the main business logic to apply
 /// operations on `Accounts`, but also used for mock tests to check we get the
 /// correct results from reading a CSV stream.
-pub(crate) trait TransactionUser {
-    fn use_tx(&mut self, tx: Transaction) -> Result<(), Error>;
+pub trait TransactionUser {
+    fn use_tx(&mut self, tx: TypedTransaction) -> Result<(), Error>;
+    /// Same as `use_tx`, for a transaction which may say when it was made; users which
+    /// don't care about time can leave it as is.
+    fn use_tx_at(
+        &mut self,
+        tx: TypedTransaction,
+        _timestamp: Option<Timestamp>,
+    ) -> Result<(), Error> {
+        self.use_tx(tx)
+    }
+    /// Called by the readers after each row, successful or not, with the position of the
+    /// next one; that's where reading can be resumed from (see `resume_transactions`).
+    fn checkpoint(&mut self, _next: Position) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+    /// Called by the readers for a row which failed before reaching `use_tx_at` (it can't
+    /// be read, or it isn't a valid transaction), before the error policy is applied;
+    /// e.g. to log it along with the transactions.
+    fn rejected(&mut self, _row: &RowError) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
 }
 
-/// Simple CSV importer for `Transaction`s.
-pub(crate) fn read_transactions<R: std::io::Read, U: TransactionUser>(
-    reader: R,
-    user: &mut U,
-) -> Result<(), anyhow::Error> {
+impl<U: TransactionUser + ?Sized> TransactionUser for &mut U {
+    fn use_tx(&mut self, tx: TypedTransaction) -> Result<(), Error> {
+        (**self).use_tx(tx)
+    }
+    fn use_tx_at(
+        &mut self,
+        tx: TypedTransaction,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), Error> {
+        (**self).use_tx_at(tx, timestamp)
+    }
+    fn checkpoint(&mut self, next: Position) -> Result<(), anyhow::Error> {
+        (**self).checkpoint(next)
+    }
+    fn rejected(&mut self, row: &RowError) -> Result<(), anyhow::Error> {
+        (**self).rejected(row)
+    }
+}
+
+/// Trait for doing something with the rows whose transaction failed, e.g. writing them
+/// somewhere so they can be corrected and fed again.
+pub trait RejectSink {
+    fn reject(
+        &mut self,
+        headers: &csv::StringRecord,
+        record: &csv::StringRecord,
+        line: u64,
+        error: &Error,
+    ) -> Result<(), anyhow::Error>;
+    /// Same as `reject`, for a row which couldn't even be read: `content` is the row as it
+    /// was read, and `record` its fields, if they could be made out (they're empty
+    /// otherwise, e.g. with too many columns). By default, it's rejected like the others.
+    fn reject_malformed(
+        &mut self,
+        headers: &csv::StringRecord,
+        record: &csv::StringRecord,
+        line: u64,
+        _content: &str,
+        error: &Error,
+    ) -> Result<(), anyhow::Error> {
+        self.reject(headers, record, line, error)
+    }
+}
+
+/// Rejects shared with something else than the reader, e.g. a `Snapshotter` syncing them
+impl<S: RejectSink + ?Sized> RejectSink for std::sync::Arc<std::sync::Mutex<S>> {
+    fn reject(
+        &mut self,
+        headers: &csv::StringRecord,
+        record: &csv::StringRecord,
+        line: u64,
+        error: &Error,
+    ) -> Result<(), anyhow::Error> {
+        let mut sink = self
+            .lock()
+            .map_err(|_| anyhow::anyhow!("rejects poisoned"))?;
+        sink.reject(headers, record, line, error)
+    }
+    fn reject_malformed(
+        &mut self,
+        headers: &csv::StringRecord,
+        record: &csv::StringRecord,
+        line: u64,
+        content: &str,
+        error: &Error,
+    ) -> Result<(), anyhow::Error> {
+        let mut sink = self
+            .lock()
+            .map_err(|_| anyhow::anyhow!("rejects poisoned"))?;
+        sink.reject_malformed(headers, record, line, content, error)
+    }
+}
+
+/// What the readers do when a row fails, be it because it can't be read (wrong number of
+/// columns, bad amount, unknown type...) or because its transaction was rejected. I/O
+/// errors always stop reading.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Report the row and go on with the next one
+    #[default]
+    Continue,
+    /// Stop at the first failed row
+    Abort,
+    /// Go on until this many rows failed
+    AbortAfter(u64),
+    /// Go on past rows which can't be read, but stop at the first rejected transaction
+    SkipMalformedRows,
+}
+
+impl ErrorPolicy {
+    /// `continue`, `abort`, `abort-after-N` or `skip-malformed-rows`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "continue" => Some(ErrorPolicy::Continue),
+            "abort" => Some(ErrorPolicy::Abort),
+            "skip-malformed-rows" => Some(ErrorPolicy::SkipMalformedRows),
+            _ => match name.strip_prefix("abort-after-")?.parse() {
+                Ok(0) | Err(_) => None,
+                Ok(failures) => Some(ErrorPolicy::AbortAfter(failures)),
+            },
+        }
+    }
+    fn aborts(self, failures: u64, malformed: bool) -> bool {
+        match self {
+            ErrorPolicy::Continue => false,
+            ErrorPolicy::Abort => true,
+            ErrorPolicy::AbortAfter(max) => failures >= max,
+            ErrorPolicy::SkipMalformedRows => !malformed,
+        }
+    }
+}
+
+/// What went wrong while reading transactions: every failed row, in order, with its
+/// context. They're all kept in memory, so a file full of failures makes for a big report.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ReadReport {
+    errors: Vec<RowError>,
+}
+
+impl ReadReport {
+    pub fn errors(&self) -> &[RowError] {
+        &self.errors
+    }
+    /// How many rows couldn't be read at all
+    pub fn malformed(&self) -> usize {
+        self.errors
+            .iter()
+            .filter(|row| matches!(row.error(), Error::MalformedRow(_)))
+            .count()
+    }
+    /// One line telling how many rows were skipped as malformed, if any, for the end of a
+    /// run (failed rows are reported one by one along the way)
+    pub fn summary(&self) -> Option<String> {
+        let malformed = self.malformed();
+        (malformed > 0).then(|| format!("{malformed} malformed row(s) skipped"))
+    }
+}
+
+/// How the readers deal with failed rows; by default they're reported on stderr, and
+/// reading goes on. Rejects must be `Send`, so the async reader can be spawned.
+#[derive(Default)]
+pub struct ReadOptions<'a> {
+    rejects: Option<&'a mut (dyn RejectSink + Send)>,
+    policy: ErrorPolicy,
+    name: Option<&'a str>,
+}
+
+impl<'a> ReadOptions<'a> {
+    /// Failed transactions go to `rejects` instead of stderr
+    pub fn with_rejects(mut self, rejects: &'a mut (dyn RejectSink + Send)) -> Self {
+        self.rejects = Some(rejects);
+        self
+    }
+    pub fn with_policy(mut self, policy: ErrorPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+    /// Name of the input, e.g. its path, to tell where failed rows come from
+    pub fn with_name(mut self, name: &'a str) -> Self {
+        self.name = Some(name);
+        self
+    }
+}
+
+/// Reports the failed rows, to `rejects` or stderr, collects them, and tells when to stop
+/// reading
+pub(crate) struct Failures<'a> {
+    policy: ErrorPolicy,
+    source: InputSource,
+    name: Option<&'a str>,
+    rejects: Option<&'a mut (dyn RejectSink + Send)>,
+    report: ReadReport,
+}
+
+impl<'a> Failures<'a> {
+    pub(crate) fn new(source: InputSource, options: ReadOptions<'a>) -> Self {
+        Self {
+            policy: options.policy,
+            source,
+            name: options.name,
+            rejects: options.rejects,
+            report: ReadReport::default(),
+        }
+    }
+    pub(crate) fn rejected(
+        &mut self,
+        headers: &csv::StringRecord,
+        record: &csv::StringRecord,
+        error: RowError,
+    ) -> Result<(), anyhow::Error> {
+        let error = error.with_input_name(self.name);
+        match self.rejects.as_deref_mut() {
+            Some(rejects) => rejects.reject(headers, record, error.line(), error.error())?,
+            None => eprintln!("{error}"),
+        }
+        self.failed(error, false)
+    }
+    /// Error of a row which couldn't be read, to be given to `malformed`: `content` is the
+    /// row as it was read, so it can be found and fixed.
+    pub(crate) fn malformed_row(&self, at: Position, content: &str, message: &str) -> RowError {
+        RowError::new(Error::MalformedRow(message.into()), at, self.source)
+            .with_input_name(self.name)
+            .with_content(content)
+    }
+    /// `record` is the fields the row of `error` was split into, if they match `headers`
+    pub(crate) fn malformed(
+        &mut self,
+        headers: &csv::StringRecord,
+        record: &csv::StringRecord,
+        error: RowError,
+    ) -> Result<(), anyhow::Error> {
+        let (line, content) = (error.line(), error.content().unwrap_or_default());
+        match self.rejects.as_deref_mut() {
+            Some(rejects) => {
+                rejects.reject_malformed(headers, record, line, content, error.error())?
+            }
+            None => {
+                let name = self
+                    .name
+                    .map(|name| format!(" of {name}"))
+                    .unwrap_or_default();
+                let byte = error.byte();
+                let message = match error.error() {
+                    Error::MalformedRow(message) => message.clone(),
+                    e => e.to_string(),
+                };
+                eprintln!("Line {line}{name} (byte {byte}) skipped, {message}:\n    {content}");
+            }
+        }
+        self.failed(error, true)
+    }
+    /// Whether failed rows go to rejects, so their fields are needed
+    #[cfg(feature = "async")]
+    pub(crate) fn has_rejects(&self) -> bool {
+        self.rejects.is_some()
+    }
+    /// Gives the report, once reading is over
+    pub(crate) fn finish(self) -> ReadReport {
+        self.report
+    }
+    /// When stopping, the row which made it stop is the cause of the returned error
+    fn failed(&mut self, error: RowError, malformed: bool) -> Result<(), anyhow::Error> {
+        let count = self.report.errors.len() as u64 + 1;
+        if self.policy.aborts(count, malformed) {
+            let line = error.line();
+            return Err(anyhow::Error::new(error).context(format!(
+                "Stopped at line {line}, after {count} failed row(s)"
+            )));
+        }
+        self.report.errors.push(error);
+        Ok(())
+    }
+}
+
+/// Parses a single CSV line, for the readers going line by line
+pub(crate) fn parse_line(line: &str) -> Result<csv::StringRecord, csv::Error> {
     let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(false)
         .trim(csv::Trim::All)
-        .from_reader(reader);
-    for result in rdr.deserialize() {
-        let mut tx: Transaction = result?;
-        if let Some(mut amount) = tx.amount {
-            amount.rescale(SIGNIFICANT_DIGITS);
-            tx.amount = Some(amount);
+        .from_reader(line.as_bytes());
+    let mut record = csv::StringRecord::new();
+    rdr.read_record(&mut record)?;
+    Ok(record)
+}
+
+/// Converts a freshly read `Transaction` and uses it; if that fails, its raw fields go to
+/// `rejects` if there's one, or the error is just reported on stderr, and it's added to
+/// the report.
+fn use_read_tx<U: TransactionUser>(
+    tx: Transaction,
+    source: InputSource,
+    user: &mut U,
+    failures: &mut Failures,
+    headers: &csv::StringRecord,
+    record: &csv::StringRecord,
+    at: Position,
+) -> Result<(), anyhow::Error> {
+    let (txtype, client, id, timestamp) = (tx.txtype, tx.client, tx.id, tx.timestamp);
+    let row_error = |e| RowError::new(e, at, source).with_tx(txtype, client, id);
+    let tx = match source
+        .check(&tx)
+        .and_then(|()| TypedTransaction::try_from(tx))
+    {
+        Ok(tx) => tx,
+        Err(e) => {
+            let error = row_error(e);
+            user.rejected(&error)?;
+            return failures.rejected(headers, record, error);
         }
-        if let Err(e) = user.use_tx(tx) {
-            // Really crude error handling, we'd want something a bit more sophisticated IRL
-            eprintln!("Transaction {} failed: {e}", tx.id);
+    };
+    match user.use_tx_at(tx, timestamp) {
+        Ok(()) => Ok(()),
+        Err(e) => failures.rejected(headers, record, row_error(e)),
+    }
+}
+
+/// Reports a row which couldn't be read to `user`, then to `failures`
+fn read_failed<U: TransactionUser>(
+    user: &mut U,
+    failures: &mut Failures,
+    at: Position,
+    headers: &csv::StringRecord,
+    record: &csv::StringRecord,
+    content: &str,
+    message: &str,
+) -> Result<(), anyhow::Error> {
+    let error = failures.malformed_row(at, content, message);
+    user.rejected(&error)?;
+    failures.malformed(headers, record, error)
+}
+
+// Fields are trimmed after reading, so malformed rows can be reported untouched
+fn csv_reader<R: std::io::Read>(reader: R) -> csv::Reader<R> {
+    csv::ReaderBuilder::new().from_reader(reader)
+}
+
+/// What's wrong with a row, without the position the reader adds to its errors
+pub(crate) fn malformed_message(error: &csv::Error) -> String {
+    match error.kind() {
+        csv::ErrorKind::Deserialize { err, .. } => err.to_string(),
+        csv::ErrorKind::UnequalLengths {
+            expected_len, len, ..
+        } => format!("found {len} fields instead of {expected_len}"),
+        _ => error.to_string(),
+    }
+}
+
+/// Writes `record` back as CSV, quoting its fields as needed
+fn raw_content(record: &csv::ByteRecord) -> String {
+    let mut wtr = csv::WriterBuilder::new()
+        .terminator(csv::Terminator::Any(b'\n'))
+        .from_writer(Vec::new());
+    // writing to memory can't fail
+    let _ = wtr.write_byte_record(record);
+    let content = wtr.into_inner().unwrap_or_default();
+    String::from_utf8_lossy(content.strip_suffix(b"\n").unwrap_or(&content)).into_owned()
+}
+
+fn read_csv<R: std::io::Read, U: TransactionUser>(
+    mut rdr: csv::Reader<R>,
+    source: InputSource,
+    user: &mut U,
+    mut failures: Failures,
+) -> Result<ReadReport, anyhow::Error> {
+    let mut headers = rdr.headers()?.clone();
+    headers.trim();
+    let mut raw = csv::ByteRecord::new();
+    loop {
+        let read = match rdr.read_byte_record(&mut raw) {
+            Ok(false) => break,
+            Ok(true) => Ok(()),
+            Err(e) if !e.is_io_error() => Err(malformed_message(&e)),
+            Err(e) => return Err(e.into()),
+        };
+        let row = read.and_then(|()| {
+            let mut record = csv::StringRecord::from_byte_record(raw.clone())
+                .map_err(|e| e.utf8_error().to_string())?;
+            record.trim();
+            let tx = record
+                .deserialize::<Transaction>(Some(&headers))
+                .map_err(|e| malformed_message(&e))?;
+            Ok((tx, record))
+        });
+        let at = raw.position().map_or(Position::default(), |position| {
+            Position::new(position.byte(), position.line())
+        });
+        match row {
+            Ok((tx, record)) => {
+                use_read_tx(tx, source, user, &mut failures, &headers, &record, at)?
+            }
+            Err(message) => {
+                let mut record = csv::StringRecord::new();
+                if raw.len() == headers.len() {
+                    record.extend(raw.iter().map(String::from_utf8_lossy));
+                    record.trim();
+                }
+                let content = raw_content(&raw);
+                read_failed(
+                    user,
+                    &mut failures,
+                    at,
+                    &headers,
+                    &record,
+                    &content,
+                    &message,
+                )?;
+            }
         }
+        user.checkpoint(Position::new(rdr.position().byte(), rdr.position().line()))?;
     }
-    Ok(())
+    Ok(failures.finish())
+}
+
+/// Simple CSV importer for `Transaction`s, which are converted to `TypedTransaction`s
+/// before being used. Administrative transactions are rejected unless `source` is
+/// `InputSource::Admin`. Failed transactions go to the rejects of `options` if there are
+/// some, or are just reported on stderr, as are rows which can't be read; the policy of
+/// `options` tells whether to go on after them. Either way, they're all in the returned
+/// report, and when stopping, the `RowError` which made it stop is the cause of the error.
+pub fn read_transactions<R: std::io::Read, U: TransactionUser>(
+    reader: R,
+    source: InputSource,
+    user: &mut U,
+    options: ReadOptions,
+) -> Result<ReadReport, anyhow::Error> {
+    read_csv(
+        csv_reader(reader),
+        source,
+        user,
+        Failures::new(source, options),
+    )
+}
+
+/// Same as `read_transactions`, but starting at `start` (as given to
+/// `TransactionUser::checkpoint`) instead of the first row.
+pub fn resume_transactions<R: std::io::Read + Seek, U: TransactionUser>(
+    reader: R,
+    start: Position,
+    source: InputSource,
+    user: &mut U,
+    options: ReadOptions,
+) -> Result<ReadReport, anyhow::Error> {
+    let mut rdr = csv_reader(reader);
+    // headers must be read before seeking past them
+    rdr.headers()?;
+    let mut position = csv::Position::new();
+    position.set_byte(start.byte()).set_line(start.line());
+    rdr.seek(position)?;
+    read_csv(rdr, source, user, Failures::new(source, options))
+}
+
+/// The fields of a JSON object, as if they were CSV columns
+fn json_fields(value: &serde_json::Value) -> (csv::StringRecord, csv::StringRecord) {
+    value
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(key, value)| {
+            let field = match value {
+                serde_json::Value::Null => String::new(),
+                serde_json::Value::String(field) => field.clone(),
+                value => value.to_string(),
+            };
+            (key.as_str(), field)
+        })
+        .unzip()
+}
+
+/// Reads a transaction from a JSON object, for those not read by `read_transactions_json`
+#[cfg(feature = "http")]
+pub(crate) fn json_transaction(value: &serde_json::Value) -> Result<Transaction, String> {
+    let (headers, record) = json_fields(value);
+    record
+        .deserialize(Some(&headers))
+        .map_err(|e| malformed_message(&e))
+}
+
+fn read_json<R: BufRead, U: TransactionUser>(
+    mut reader: R,
+    mut position: Position,
+    source: InputSource,
+    user: &mut U,
+    mut failures: Failures,
+) -> Result<ReadReport, anyhow::Error> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = reader.read_line(&mut line)?;
+        if read == 0 {
+            break;
+        }
+        let start = position;
+        position = Position::new(position.byte() + read as u64, position.line() + 1);
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value = match serde_json::from_str::<serde_json::Value>(line.trim_end()) {
+            Ok(value) => value,
+            Err(e) => {
+                let (none, line) = (csv::StringRecord::new(), line.trim_end());
+                read_failed(
+                    user,
+                    &mut failures,
+                    start,
+                    &none,
+                    &none,
+                    line,
+                    &e.to_string(),
+                )?;
+                user.checkpoint(position)?;
+                continue;
+            }
+        };
+        // fields are read as their text, like CSV ones, so numeric amounts keep every digit
+        let (headers, record) = json_fields(&value);
+        let tx = match record.deserialize::<Transaction>(Some(&headers)) {
+            Ok(tx) => tx,
+            Err(e) => {
+                let (line, message) = (line.trim_end(), malformed_message(&e));
+                read_failed(
+                    user,
+                    &mut failures,
+                    start,
+                    &headers,
+                    &record,
+                    line,
+                    &message,
+                )?;
+                user.checkpoint(position)?;
+                continue;
+            }
+        };
+        use_read_tx(tx, source, user, &mut failures, &headers, &record, start)?;
+        user.checkpoint(position)?;
+    }
+    Ok(failures.finish())
+}
+
+/// JSON Lines importer for `Transaction`s, one object per line with the same fields as the
+/// CSV columns; otherwise it works just like `read_transactions`. For rejects, the raw
+/// fields are the object's values.
+pub fn read_transactions_json<R: std::io::Read, U: TransactionUser>(
+    reader: R,
+    source: InputSource,
+    user: &mut U,
+    options: ReadOptions,
+) -> Result<ReadReport, anyhow::Error> {
+    let reader = std::io::BufReader::new(reader);
+    let failures = Failures::new(source, options);
+    read_json(reader, Position::new(0, 1), source, user, failures)
+}
+
+/// Same as `read_transactions_json`, but starting at `start` (as given to
+/// `TransactionUser::checkpoint`) instead of the first line.
+pub fn resume_transactions_json<R: std::io::Read + Seek, U: TransactionUser>(
+    mut reader: R,
+    start: Position,
+    source: InputSource,
+    user: &mut U,
+    options: ReadOptions,
+) -> Result<ReadReport, anyhow::Error> {
+    reader.seek(std::io::SeekFrom::Start(start.byte()))?;
+    let reader = std::io::BufReader::new(reader);
+    read_json(reader, start, source, user, Failures::new(source, options))
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{
-        data::{Transaction, TxType::*},
-        read::{read_transactions, TransactionUser},
+        compute::Accounts,
+        data::Position,
+        data::{
+            amount, AdminInfo, Error, Format, InputSource, RowError, Timestamp, Transaction,
+            TxType, TypedTransaction, TypedTransaction::*,
+        },
+        read::{
+            raw_content, read_transactions, read_transactions_json, resume_transactions_json,
+            ErrorPolicy, ReadOptions, TransactionUser,
+        },
+        write::RejectWriter,
     };
     use rust_decimal_macros::dec;
 
+    #[derive(Default)]
+    struct TxStorage {
+        txst: Vec<TypedTransaction>,
+    }
+    impl TransactionUser for TxStorage {
+        fn use_tx(&mut self, tx: TypedTransaction) -> Result<(), Error> {
+            self.txst.push(tx);
+            Ok(())
+        }
+    }
+
     #[test]
     fn read_tx() {
-        #[derive(Default)]
-        struct TxStorage {
-            txst: Vec<Transaction>,
-        }
-        impl TransactionUser for TxStorage {
-            fn use_tx(&mut self, tx: crate::data::Transaction) -> Result<(), crate::data::Error> {
-                Ok(self.txst.push(tx))
-            }
-        }
         let mut storage = TxStorage::default();
         let transactions_csv = b"\
 type,       client, tx, amount
@@ -59,47 +617,565 @@ withdrawal, 1,      4,  1.5
 withdrawal, 2,      5,  3.0
 dispute,    1,      3,
 ";
-        read_transactions(&transactions_csv[..], &mut storage).unwrap();
+        read_transactions(
+            &transactions_csv[..],
+            InputSource::User,
+            &mut storage,
+            ReadOptions::default(),
+        )
+        .unwrap();
         assert_eq!(
             storage.txst,
             [
-                Transaction {
-                    txtype: Deposit,
+                Deposit {
                     client: 1,
                     id: 1,
-                    amount: Some(dec!(1.0))
+                    amount: amount!(1.0)
                 },
-                Transaction {
-                    txtype: Deposit,
+                Deposit {
                     client: 2,
                     id: 2,
-                    amount: Some(dec!(2.0))
+                    amount: amount!(2.0)
                 },
-                Transaction {
-                    txtype: Deposit,
+                Deposit {
                     client: 1,
                     id: 3,
-                    amount: Some(dec!(2.0))
+                    amount: amount!(2.0)
                 },
-                Transaction {
-                    txtype: Withdrawal,
+                Withdrawal {
                     client: 1,
                     id: 4,
-                    amount: Some(dec!(1.5))
+                    amount: amount!(1.5)
                 },
-                Transaction {
-                    txtype: Withdrawal,
+                Withdrawal {
                     client: 2,
                     id: 5,
-                    amount: Some(dec!(3.0))
+                    amount: amount!(3.0)
+                },
+                Dispute { client: 1, id: 3 },
+            ]
+        )
+    }
+    #[test]
+    fn read_timestamped_tx() {
+        #[derive(Default)]
+        struct Timestamps(Vec<Option<Timestamp>>);
+        impl TransactionUser for Timestamps {
+            fn use_tx(&mut self, _tx: TypedTransaction) -> Result<(), Error> {
+                unreachable!("the readers give the timestamp")
+            }
+            fn use_tx_at(
+                &mut self,
+                _tx: TypedTransaction,
+                timestamp: Option<Timestamp>,
+            ) -> Result<(), Error> {
+                self.0.push(timestamp);
+                Ok(())
+            }
+        }
+        let transactions_csv = b"\
+type,       client, tx, amount, timestamp
+deposit,    1,      1,  1.0,    1700000000
+deposit,    2,      2,  2.0,
+dispute,    1,      1,  ,       1700000060
+";
+        let mut timestamps = Timestamps::default();
+        read_transactions(
+            &transactions_csv[..],
+            InputSource::User,
+            &mut timestamps,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(timestamps.0, [Some(1700000000), None, Some(1700000060)]);
+        let transactions_json = br#"
+{"type": "deposit", "client": 1, "tx": 1, "amount": "1.0", "timestamp": 1700000000}
+{"type": "dispute", "client": 1, "tx": 1}
+"#;
+        let mut timestamps = Timestamps::default();
+        read_transactions_json(
+            &transactions_json[..],
+            InputSource::User,
+            &mut timestamps,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(timestamps.0, [Some(1700000000), None]);
+    }
+    #[test]
+    fn read_inconsistent_tx() {
+        let mut storage = TxStorage::default();
+        let transactions_csv = b"\
+type,       client, tx, amount
+deposit,    1,      1,
+dispute,    1,      1,  1.0
+unlock,     1,      2,
+withdrawal, 1,      3,  1.0
+";
+        read_transactions(
+            &transactions_csv[..],
+            InputSource::Admin,
+            &mut storage,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            storage.txst,
+            [Withdrawal {
+                client: 1,
+                id: 3,
+                amount: amount!(1.0)
+            }]
+        );
+    }
+    #[test]
+    fn typed_tx() {
+        let tx = |txtype, amount, operator: Option<&str>| Transaction {
+            txtype,
+            client: 5,
+            id: 1,
+            amount,
+            operator: operator.map(Into::into),
+            reason: None,
+            timestamp: None,
+        };
+        assert_eq!(
+            TypedTransaction::try_from(tx(TxType::Deposit, None, None)),
+            Err(Error::MissingAmount)
+        );
+        assert_eq!(
+            TypedTransaction::try_from(tx(TxType::Deposit, Some(dec!(-100)), None)),
+            Err(Error::NegativeAmount)
+        );
+        assert_eq!(
+            TypedTransaction::try_from(tx(TxType::Withdrawal, Some(dec!(-100)), None)),
+            Err(Error::NegativeAmount)
+        );
+        assert_eq!(
+            TypedTransaction::try_from(tx(TxType::Deposit, Some(dec!(1.23456)), None)),
+            Ok(Deposit {
+                client: 5,
+                id: 1,
+                amount: amount!(1.2346)
+            })
+        );
+        assert_eq!(
+            TypedTransaction::try_from(tx(TxType::Dispute, Some(dec!(100)), None)),
+            Err(Error::UnattendedforAmount)
+        );
+        assert_eq!(
+            TypedTransaction::try_from(tx(TxType::Lock, None, None)),
+            Err(Error::MissingOperator)
+        );
+        assert_eq!(
+            TypedTransaction::try_from(tx(TxType::Lock, Some(dec!(100)), Some("alice"))),
+            Err(Error::UnattendedforAmount)
+        );
+        assert_eq!(
+            TypedTransaction::try_from(tx(TxType::Lock, None, Some("alice"))),
+            Ok(Lock {
+                client: 5,
+                id: 1,
+                admin: AdminInfo {
+                    operator: "alice".into(),
+                    reason: None
+                }
+            })
+        );
+    }
+    #[test]
+    fn read_admin_tx() {
+        let transactions_csv = b"\
+type,       client, tx, amount, operator, reason
+deposit,    1,      1,  1.0,    ,
+unlock,     1,      2,  ,       alice,    chargeback reversed
+";
+        let mut storage = TxStorage::default();
+        read_transactions(
+            &transactions_csv[..],
+            InputSource::User,
+            &mut storage,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(storage.txst.len(), 1);
+        let mut storage = TxStorage::default();
+        read_transactions(
+            &transactions_csv[..],
+            InputSource::Admin,
+            &mut storage,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            storage.txst[1],
+            Unlock {
+                client: 1,
+                id: 2,
+                admin: AdminInfo {
+                    operator: "alice".into(),
+                    reason: Some("chargeback reversed".into())
+                }
+            }
+        );
+    }
+    #[test]
+    fn read_error_policy() {
+        let transactions_csv = b"\
+type,       client, tx, amount
+deposit,    1,      1,  1.0
+deposit,    1,      2,  lots
+withdrawal, 1,      3,  5.0
+
+deposit,    1,      4,  2.0
+{\"type\": \"deposit\", \"client\": 1}
+";
+        let read = |policy| {
+            let mut accounts = Accounts::new();
+            read_transactions(
+                &transactions_csv[..],
+                InputSource::User,
+                &mut accounts,
+                ReadOptions::default().with_policy(policy),
+            )
+            .map(|_| accounts.account(1).unwrap().total())
+            .map_err(|e| e.to_string())
+        };
+        assert_eq!(read(ErrorPolicy::Continue), Ok(dec!(3)));
+        assert_eq!(read(ErrorPolicy::AbortAfter(4)), Ok(dec!(3)));
+        assert_eq!(
+            read(ErrorPolicy::Abort),
+            Err("Stopped at line 3, after 1 failed row(s)".into())
+        );
+        assert_eq!(
+            read(ErrorPolicy::AbortAfter(2)),
+            Err("Stopped at line 4, after 2 failed row(s)".into())
+        );
+        assert_eq!(
+            read(ErrorPolicy::SkipMalformedRows),
+            Err("Stopped at line 4, after 2 failed row(s)".into())
+        );
+        assert_eq!(
+            read(ErrorPolicy::AbortAfter(3)),
+            Err("Stopped at line 7, after 3 failed row(s)".into())
+        );
+        assert_eq!(
+            ErrorPolicy::from_name("abort-after-10"),
+            Some(ErrorPolicy::AbortAfter(10))
+        );
+        assert_eq!(ErrorPolicy::from_name("abort-after-0"), None);
+    }
+    #[test]
+    fn read_report() {
+        let transactions_csv = b"\
+type,       client, tx, amount
+deposit,    1,      1,  1.0
+withdrawal, 1,      2,  5.0
+deposit,    1,      3,  lots
+lock,       2,      4,
+";
+        let report = read_transactions(
+            &transactions_csv[..],
+            InputSource::User,
+            &mut Accounts::new(),
+            ReadOptions::default(),
+        )
+        .unwrap();
+        let errors = report.errors();
+        assert_eq!(errors.len(), 3);
+        assert_eq!(report.malformed(), 1);
+        assert_eq!(
+            report.summary().as_deref(),
+            Some("1 malformed row(s) skipped")
+        );
+        assert_eq!(
+            errors[0].to_string(),
+            "Line 3, withdrawal #2 of client 1: \
+             Insufficient funds for operation (asked 5.0000 while 1.0000 available)"
+        );
+        assert_eq!(errors[0].error().numeric_code(), 3);
+        assert_eq!(
+            (
+                errors[1].line(),
+                errors[1].client(),
+                errors[1].error().code()
+            ),
+            (4, None, "malformed_row")
+        );
+        assert_eq!(
+            (
+                errors[2].txtype(),
+                errors[2].client(),
+                errors[2].id(),
+                errors[2].input()
+            ),
+            (Some(TxType::Lock), Some(2), Some(4), InputSource::User)
+        );
+        let error = read_transactions(
+            &transactions_csv[..],
+            InputSource::User,
+            &mut Accounts::new(),
+            ReadOptions::default().with_policy(ErrorPolicy::Abort),
+        )
+        .unwrap_err();
+        let row = error.downcast_ref::<RowError>().unwrap();
+        assert_eq!((row.line(), row.id()), (3, Some(2)));
+        let report = read_transactions(
+            &transactions_csv[..],
+            InputSource::User,
+            &mut Accounts::new(),
+            ReadOptions::default().with_name("transactions.csv"),
+        )
+        .unwrap();
+        assert_eq!(report.errors()[1].input_name(), Some("transactions.csv"));
+        assert_eq!(
+            report.errors()[2].to_string(),
+            "Line 5 of transactions.csv, lock #4 of client 2: \
+             Administrative transaction from a non-admin source"
+        );
+    }
+    #[test]
+    fn read_malformed_rows() {
+        let transactions_csv = b"\
+type,       client, tx, amount
+deposit,    1,      1,  1.0
+deposit,    1,      2,  1.0.0
+refund,     1,      3,  1.0
+deposit,    1,      4,  1.0,\"oops, again\"
+deposit,    \xff,   5,  1.0
+deposit,    1,      6
+deposit,    1,      7,  2.0
+";
+        let mut accounts = Accounts::new();
+        let report = read_transactions(
+            &transactions_csv[..],
+            InputSource::User,
+            &mut accounts,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(accounts.account(1).unwrap().total(), dec!(3));
+        assert_eq!(report.malformed(), 5);
+        assert_eq!(
+            report.summary().as_deref(),
+            Some("5 malformed row(s) skipped")
+        );
+        let rows: Vec<_> = report
+            .errors()
+            .iter()
+            .map(|row| (row.line(), row.byte(), row.content().unwrap()))
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                (3, 59, "deposit,    1,      2,  1.0.0"),
+                (4, 89, "refund,     1,      3,  1.0"),
+                (5, 117, "deposit,    1,      4,  1.0,\"oops, again\""),
+                (6, 159, "deposit,    \u{fffd},   5,  1.0"),
+                (7, 184, "deposit,    1,      6"),
+            ]
+        );
+        let record = csv::ByteRecord::from(vec!["deposit", "    1", "oops, again"]);
+        assert_eq!(raw_content(&record), "deposit,    1,\"oops, again\"");
+    }
+    #[test]
+    fn read_rejects() {
+        struct Rejecting;
+        impl TransactionUser for Rejecting {
+            fn use_tx(&mut self, tx: TypedTransaction) -> Result<(), Error> {
+                match tx {
+                    Withdrawal { id, .. } => Err(Error::TransactionNotFound(id)),
+                    _ => Ok(()),
+                }
+            }
+        }
+        let transactions_csv = b"\
+type,       client, tx, amount
+deposit,    1,      1,  1.0
+withdrawal, 1,      2,  1.5
+deposit,    1,      3,
+deposit,    1,      4,  lots
+deposit,    1,      5,  1.0,  2.0
+";
+        let mut rejects = RejectWriter::new(Vec::new(), Format::Csv);
+        read_transactions(
+            &transactions_csv[..],
+            InputSource::User,
+            &mut Rejecting,
+            ReadOptions::default().with_rejects(&mut rejects),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(rejects.into_inner().unwrap()).unwrap(),
+            "\
+type,client,tx,amount,operator,reason,timestamp,line,error,message,content
+withdrawal,1,2,1.5,,,,3,transaction_not_found,Transaction #2 not found,
+deposit,1,3,,,,,4,missing_amount,Transaction amount is missing for dispute/withdrawal,
+deposit,1,4,lots,,,,5,malformed_row,\
+\"Malformed row: invalid value: string \"\"lots\"\", expected a Decimal type representing a fixed-point number\",\
+\"deposit,    1,      4,  lots\"
+,,,,,,,6,malformed_row,Malformed row: found 5 fields instead of 4,\
+\"deposit,    1,      5,  1.0,  2.0\"
+"
+        );
+    }
+    #[test]
+    fn read_json_rejects() {
+        // rows with different fields must still line up
+        let transactions_json = br#"
+{"type": "deposit", "client": 1, "tx": 1, "amount": "1.0"}
+{"type": "dispute", "client": 1, "tx": 7}
+{"type": "withdrawal", "client": 1, "tx": 2, "amount": "5.0", "timestamp": 1700000000}
+{"type": "lock", "client": 1, "tx": 3, "operator": "alice"}
+{"type": "refund", "client": 1, "tx": 4}
+{"type": "deposit",
+"#;
+        let mut rejects = RejectWriter::new(Vec::new(), Format::Csv);
+        read_transactions_json(
+            &transactions_json[..],
+            InputSource::User,
+            &mut Accounts::new(),
+            ReadOptions::default().with_rejects(&mut rejects),
+        )
+        .unwrap();
+        let rejects = rejects.into_inner().unwrap();
+        assert_eq!(
+            String::from_utf8(rejects.clone()).unwrap(),
+            "\
+type,client,tx,amount,operator,reason,timestamp,line,error,message,content
+dispute,1,7,,,,,3,transaction_not_found,Transaction #7 not found,
+withdrawal,1,2,5.0,,,1700000000,4,insufficient_funds,\
+Insufficient funds for operation (asked 5.0000 while 1.0000 available),
+lock,1,3,,alice,,,5,admin_only,Administrative transaction from a non-admin source,
+refund,1,4,,,,,6,malformed_row,\
+\"Malformed row: unknown variant `refund`, expected one of `deposit`, `withdrawal`, \
+`dispute`, `resolve`, `chargeback`, `lock`, `unlock`, `close`\",\
+\"{\"\"type\"\": \"\"refund\"\", \"\"client\"\": 1, \"\"tx\"\": 4}\"
+,,,,,,,7,malformed_row,Malformed row: EOF while parsing a value at line 1 column 19,\
+\"{\"\"type\"\": \"\"deposit\"\",\"
+"
+        );
+        // and can be fed again
+        let mut storage = TxStorage::default();
+        read_transactions(
+            &rejects[..],
+            InputSource::Admin,
+            &mut storage,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(storage.txst.len(), 3);
+    }
+    #[test]
+    fn read_json_tx() {
+        let mut storage = TxStorage::default();
+        let transactions_json = br#"
+{"type": "deposit", "client": 1, "tx": 1, "amount": "1.0"}
+{"type": "withdrawal", "client": 1, "tx": 2, "amount": "0.5"}
+
+{"type": "dispute", "client": 1, "tx": 1, "amount": null}
+{"type": "resolve", "client": 1, "tx": 1}
+"#;
+        read_transactions_json(
+            &transactions_json[..],
+            InputSource::User,
+            &mut storage,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            storage.txst,
+            [
+                Deposit {
+                    client: 1,
+                    id: 1,
+                    amount: amount!(1.0)
                 },
-                Transaction {
-                    txtype: Dispute,
+                Withdrawal {
                     client: 1,
-                    id: 3,
-                    amount: None
+                    id: 2,
+                    amount: amount!(0.5)
+                },
+                Dispute { client: 1, id: 1 },
+                Resolve { client: 1, id: 1 },
+            ]
+        );
+    }
+    #[test]
+    fn read_json_numeric_amount() {
+        let mut storage = TxStorage::default();
+        // the second one doesn't fit in a float
+        let transactions_json = br#"
+{"type": "deposit", "client": 1, "tx": 1, "amount": 1.5}
+{"type": "deposit", "client": 1, "tx": 2, "amount": 9007199254740993.0001}
+"#;
+        let report = read_transactions_json(
+            &transactions_json[..],
+            InputSource::User,
+            &mut storage,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        assert!(report.errors().is_empty());
+        assert_eq!(
+            storage.txst,
+            [
+                Deposit {
+                    client: 1,
+                    id: 1,
+                    amount: amount!(1.5)
+                },
+                Deposit {
+                    client: 1,
+                    id: 2,
+                    amount: amount!(9007199254740993.0001)
                 },
             ]
+        );
+    }
+    #[test]
+    fn resume_json_tx() {
+        #[derive(Default)]
+        struct Checkpoints {
+            txst: Vec<TypedTransaction>,
+            positions: Vec<Position>,
+        }
+        impl TransactionUser for Checkpoints {
+            fn use_tx(&mut self, tx: TypedTransaction) -> Result<(), Error> {
+                self.txst.push(tx);
+                Ok(())
+            }
+            fn checkpoint(&mut self, next: Position) -> Result<(), anyhow::Error> {
+                self.positions.push(next);
+                Ok(())
+            }
+        }
+        let transactions_json = br#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1.0"}
+{"type": "deposit", "client": 1, "tx": 2, "amount": "2.0"}
+{"type": "dispute", "client": 1, "tx": 1}
+"#;
+        let mut checkpoints = Checkpoints::default();
+        read_transactions_json(
+            &transactions_json[..],
+            InputSource::User,
+            &mut checkpoints,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            checkpoints.positions[2].byte(),
+            transactions_json.len() as u64
+        );
+        let mut resumed = Checkpoints::default();
+        resume_transactions_json(
+            std::io::Cursor::new(&transactions_json[..]),
+            checkpoints.positions[0],
+            InputSource::User,
+            &mut resumed,
+            ReadOptions::default(),
         )
+        .unwrap();
+        assert_eq!(resumed.txst, checkpoints.txst[1..]);
+        assert_eq!(resumed.positions, checkpoints.positions[1..]);
     }
 }
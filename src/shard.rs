@@ -0,0 +1,272 @@
+use crate::{
+    compute::{Accounts, DisputePolicy},
+    data::{ClientId, Error, Timestamp, TxId, TypedTransaction},
+    read::TransactionUser,
+};
+use std::{collections::HashMap, sync::mpsc, thread};
+
+/// Number of transactions sent at once to a shard
+const BATCH: usize = 1024;
+/// Number of batches a shard can lag behind before the reader waits for it
+const QUEUE: usize = 16;
+
+enum Message {
+    /// Transactions, along with their rank in the input and their timestamp
+    Transactions(Vec<(u64, TypedTransaction, Option<Timestamp>)>),
+    /// Asks whether a deposit or withdrawal was accepted with this id
+    Contains(TxId, mpsc::Sender<bool>),
+}
+
+struct Shard {
+    sender: mpsc::SyncSender<Message>,
+    pending: Vec<(u64, TypedTransaction, Option<Timestamp>)>,
+    handle: thread::JoinHandle<(Accounts, Vec<u64>)>,
+}
+
+/// Applies the transactions it receives, reporting failures like `read_transactions`
+/// without a rejects sink. Gives back its accounts, along with the rank of the
+/// administrative transactions they audited so the audits can be merged in order.
+fn run_shard(mut accounts: Accounts, receiver: mpsc::Receiver<Message>) -> (Accounts, Vec<u64>) {
+    let mut audit_ranks = Vec::new();
+    for message in receiver {
+        match message {
+            Message::Transactions(txs) => {
+                for (rank, tx, timestamp) in txs {
+                    let id = tx.id();
+                    let audited = accounts.audit.len();
+                    if let Err(e) = accounts.use_tx_at(tx, timestamp) {
+                        eprintln!("Transaction {id} failed: {e}");
+                    }
+                    if accounts.audit.len() > audited {
+                        audit_ranks.push(rank);
+                    }
+                }
+            }
+            Message::Contains(id, reply) => {
+                let _ = reply.send(accounts.txset.transactions.contains_key(&id));
+            }
+        }
+    }
+    (accounts, audit_ranks)
+}
+
+/// `TransactionUser` spreading the transactions over worker threads, each owning the
+/// `Accounts` of the clients it's given (by client id modulo the number of shards).
+/// Since a client's transactions all go to the same shard, in order, and transactions
+/// only touch their client's account, the merged result is the same as when processing
+/// everything on a single thread.
+///
+/// The exception is transaction ids, which are global: a deposit or withdrawal reusing the
+/// id of another client's one must be rejected if that one was accepted. The owner of
+/// each id is tracked here, and in the (unusual) case of a reuse across shards, we wait
+/// for the shard of the previous owner to tell whether it accepted it. A dispute of
+/// another client's transaction is rejected as well, though as `TransactionNotFound`
+/// instead of `DisputeMismatch`.
+///
+/// Failures in the shards are only reported on stderr; `use_tx` itself only fails for
+/// such reused ids.
+pub struct ShardedAccounts {
+    policy: DisputePolicy,
+    window: Option<Timestamp>,
+    shards: Vec<Shard>,
+    owners: HashMap<TxId, ClientId>,
+    rank: u64,
+}
+
+impl ShardedAccounts {
+    /// Starts `shards` worker threads (at least one), applying the given `DisputePolicy`
+    /// and dispute window (see `Accounts::with_dispute_window`)
+    pub fn new(shards: usize, policy: DisputePolicy, window: Option<Timestamp>) -> Self {
+        let shards = (0..shards.max(1))
+            .map(|_| {
+                let (sender, receiver) = mpsc::sync_channel(QUEUE);
+                let mut accounts = Accounts::new().with_dispute_policy(policy);
+                accounts.window = window;
+                Shard {
+                    sender,
+                    pending: Vec::with_capacity(BATCH),
+                    handle: thread::spawn(move || run_shard(accounts, receiver)),
+                }
+            })
+            .collect();
+        Self {
+            policy,
+            window,
+            shards,
+            owners: HashMap::new(),
+            rank: 0,
+        }
+    }
+    fn shard(&self, client: ClientId) -> usize {
+        client as usize % self.shards.len()
+    }
+    fn send(&self, shard: usize, message: Message) {
+        // a shard only stops on a panic, which is reported when joining it
+        let _ = self.shards[shard].sender.send(message);
+    }
+    fn flush(&mut self, shard: usize) {
+        let txs = std::mem::replace(&mut self.shards[shard].pending, Vec::with_capacity(BATCH));
+        if !txs.is_empty() {
+            self.send(shard, Message::Transactions(txs));
+        }
+    }
+    /// Waits for the shards to process everything, and merges their accounts.
+    pub fn finish(mut self) -> Result<Accounts, anyhow::Error> {
+        for shard in 0..self.shards.len() {
+            self.flush(shard);
+        }
+        let mut merged = Accounts::new().with_dispute_policy(self.policy);
+        merged.window = self.window;
+        let mut audit = Vec::new();
+        for shard in self.shards {
+            drop(shard.sender);
+            let (accounts, ranks) = shard
+                .handle
+                .join()
+                .map_err(|_| anyhow::anyhow!("a shard thread panicked"))?;
+            merged.accounts.extend(accounts.accounts);
+            merged
+                .txset
+                .transactions
+                .extend(accounts.txset.transactions);
+            audit.extend(ranks.into_iter().zip(accounts.audit));
+        }
+        audit.sort_by_key(|(rank, _)| *rank);
+        merged.audit = audit.into_iter().map(|(_, tx)| tx).collect();
+        Ok(merged)
+    }
+}
+
+impl TransactionUser for ShardedAccounts {
+    fn use_tx(&mut self, tx: TypedTransaction) -> Result<(), Error> {
+        self.use_tx_at(tx, None)
+    }
+    fn use_tx_at(
+        &mut self,
+        tx: TypedTransaction,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), Error> {
+        let client = tx.client();
+        let shard = self.shard(client);
+        if let TypedTransaction::Deposit { id, .. } | TypedTransaction::Withdrawal { id, .. } = tx {
+            // an accepted transaction with this id can only be in the owner's shard
+            if let Some(&owner) = self.owners.get(&id) {
+                let owner_shard = self.shard(owner);
+                if owner_shard != shard {
+                    self.flush(owner_shard);
+                    let (reply, answer) = mpsc::channel();
+                    self.send(owner_shard, Message::Contains(id, reply));
+                    if answer.recv().unwrap_or(false) {
+                        return Err(Error::DuplicateTransaction(id));
+                    }
+                }
+            }
+            self.owners.insert(id, client);
+        }
+        self.rank += 1;
+        self.shards[shard].pending.push((self.rank, tx, timestamp));
+        if self.shards[shard].pending.len() >= BATCH {
+            self.flush(shard);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ShardedAccounts;
+    use crate::{
+        compute::{Accounts, DisputePolicy},
+        data::{amount, AdminInfo, Error, TypedTransaction, TypedTransaction::*},
+        read::TransactionUser,
+    };
+
+    /// Pseudo-random mix of every kind of transaction, with plenty of failures and of
+    /// ids reused across clients.
+    fn transactions(count: u32) -> Vec<TypedTransaction> {
+        let mut seed = 42u64;
+        let mut next = |bound: u32| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) as u32 % bound
+        };
+        (0..count)
+            .map(|n| {
+                let client = next(20) as u16;
+                let id = if next(10) == 0 { next(n + 1) } else { n };
+                let admin = || AdminInfo::new("ops");
+                match next(20) {
+                    0..=7 => Deposit {
+                        client,
+                        id,
+                        amount: amount!(10),
+                    },
+                    8..=12 => Withdrawal {
+                        client,
+                        id,
+                        amount: amount!(7),
+                    },
+                    13 | 14 => Dispute { client, id },
+                    15 => Resolve { client, id },
+                    16 => Chargeback { client, id },
+                    17 => Unlock {
+                        client,
+                        id,
+                        admin: admin(),
+                    },
+                    18 => Lock {
+                        client,
+                        id,
+                        admin: admin(),
+                    },
+                    _ => Close {
+                        client,
+                        id,
+                        admin: admin(),
+                    },
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sharded_same_result() {
+        let policy = DisputePolicy::DepositsAndWithdrawals;
+        let mut expected = Accounts::new().with_dispute_policy(policy);
+        let mut shards = ShardedAccounts::new(4, policy, None);
+        for tx in transactions(20_000) {
+            let _ = expected.use_tx(tx.clone());
+            let _ = shards.use_tx(tx);
+        }
+        assert!(!expected.audit.is_empty());
+        assert!(expected.accounts().any(|account| account.locked()));
+        assert_eq!(shards.finish().unwrap(), expected);
+    }
+    #[test]
+    fn sharded_reused_id() {
+        let mut shards = ShardedAccounts::new(2, DisputePolicy::default(), None);
+        let deposit = |client, id| Deposit {
+            client,
+            id,
+            amount: amount!(1),
+        };
+        shards.use_tx(deposit(1, 1)).unwrap();
+        assert_eq!(
+            shards.use_tx(deposit(2, 1)),
+            Err(Error::DuplicateTransaction(1))
+        );
+        // a rejected transaction doesn't burn its id
+        shards
+            .use_tx(Withdrawal {
+                client: 1,
+                id: 2,
+                amount: amount!(5),
+            })
+            .unwrap();
+        shards.use_tx(deposit(2, 2)).unwrap();
+        let accounts = shards.finish().unwrap();
+        assert_eq!(accounts.account(2).unwrap().available(), amount!(1));
+        assert_eq!(accounts.txset.transactions.len(), 2);
+    }
+}
@@ -0,0 +1,291 @@
+use crate::{
+    compute::{Accounts, DisputePolicy},
+    data::{
+        Account, Amount, ClientId, Error, Position, StoredTx, Timestamp, TxId, TypedTransaction,
+    },
+    ledger::Ledger,
+    read::TransactionUser,
+    store::TxIdSet,
+    write::RejectWriter,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// Version of the snapshot format; it must be bumped whenever `Snapshot` (or anything
+/// it contains) changes, so we don't restore garbage from an older snapshot.
+const SNAPSHOT_VERSION: u32 = 5;
+
+/// `Account` serializes to the output format, which lacks `closed`; so here's the
+/// complete state of an account.
+#[derive(Serialize, Deserialize)]
+struct SnapshotAccount {
+    client: ClientId,
+    available: Amount,
+    held: Amount,
+    locked: bool,
+    closed: bool,
+}
+
+/// Everything needed to restore `Accounts`, and to resume reading the input where the
+/// snapshot was taken (and writing the rejects, from their length at the time). The big
+/// collections are borrowed when saving, to avoid a copy.
+#[derive(Serialize, Deserialize)]
+struct Snapshot<'a> {
+    version: u32,
+    position: Position,
+    rejects: Option<u64>,
+    policy: DisputePolicy,
+    window: Option<Timestamp>,
+    accounts: Vec<SnapshotAccount>,
+    transactions: Cow<'a, HashMap<TxId, StoredTx>>,
+    evicted: Cow<'a, TxIdSet>,
+    audit: Cow<'a, [TypedTransaction]>,
+    ledger: Option<Cow<'a, Ledger>>,
+}
+
+impl Accounts {
+    /// Writes a snapshot of the whole state, taken when the input was at `position` and
+    /// the rejects file (if any) was `rejects` bytes long.
+    pub fn save_snapshot<W: Write>(
+        &self,
+        writer: W,
+        position: Position,
+        rejects: Option<u64>,
+    ) -> Result<(), anyhow::Error> {
+        let snapshot = Snapshot {
+            version: SNAPSHOT_VERSION,
+            position,
+            rejects,
+            policy: self.policy,
+            window: self.window,
+            accounts: self
+                .accounts
+                .values()
+                .map(|account| SnapshotAccount {
+                    client: account.client,
+                    available: account.available,
+                    held: account.held,
+                    locked: account.locked,
+                    closed: account.closed,
+                })
+                .collect(),
+            transactions: Cow::Borrowed(&self.txset.transactions),
+            evicted: Cow::Borrowed(&self.evicted),
+            audit: Cow::Borrowed(&self.audit),
+            ledger: self.ledger.as_ref().map(Cow::Borrowed),
+        };
+        serde_json::to_writer(writer, &snapshot)?;
+        Ok(())
+    }
+    /// Restores a snapshot written by `save_snapshot`, along with its input position and
+    /// rejects length.
+    pub fn load_snapshot<R: Read>(
+        reader: R,
+    ) -> Result<(Self, Position, Option<u64>), anyhow::Error> {
+        let snapshot: Snapshot = serde_json::from_reader(reader)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            anyhow::bail!(
+                "unsupported snapshot version {} (expected {SNAPSHOT_VERSION})",
+                snapshot.version
+            );
+        }
+        let mut accounts = Accounts::new().with_dispute_policy(snapshot.policy);
+        for account in snapshot.accounts {
+            accounts.accounts.insert(
+                account.client,
+                Account {
+                    client: account.client,
+                    available: account.available,
+                    held: account.held,
+                    locked: account.locked,
+                    closed: account.closed,
+                },
+            );
+        }
+        accounts.txset.transactions = snapshot.transactions.into_owned();
+        accounts.evicted = snapshot.evicted.into_owned();
+        accounts.audit = snapshot.audit.into_owned();
+        accounts.window = snapshot.window;
+        accounts.ledger = snapshot.ledger.map(Cow::into_owned);
+        Ok((accounts, snapshot.position, snapshot.rejects))
+    }
+    /// Same as `save_snapshot`, to a file. The snapshot is written aside then renamed,
+    /// so a crash while writing it doesn't lose the previous one.
+    pub fn save_snapshot_file(
+        &self,
+        path: &Path,
+        position: Position,
+        rejects: Option<u64>,
+    ) -> Result<(), anyhow::Error> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+        self.save_snapshot(&mut file, position, rejects)?;
+        file.into_inner()?.sync_all()?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Rejects synced along with the snapshots, see `Snapshotter::with_rejects`
+pub type SharedRejects = Arc<Mutex<RejectWriter<BufWriter<File>>>>;
+
+/// `TransactionUser` applying transactions to `Accounts`, and saving a snapshot of them to
+/// `path` every `every` input rows. Each snapshot is a complete JSON dump of the state,
+/// which grows with the input, so the total work grows quadratically with it: `every` must
+/// be big enough for the snapshots to stay a small part of the processing.
+pub struct Snapshotter<'a> {
+    accounts: &'a mut Accounts,
+    path: PathBuf,
+    every: u64,
+    rows: u64,
+    last: Option<Position>,
+    rejects: Option<SharedRejects>,
+}
+
+impl<'a> Snapshotter<'a> {
+    pub fn new(accounts: &'a mut Accounts, path: impl Into<PathBuf>, every: u64) -> Self {
+        Self {
+            accounts,
+            path: path.into(),
+            every: every.max(1),
+            rows: 0,
+            last: None,
+            rejects: None,
+        }
+    }
+    /// Syncs `rejects` (also given to the reader) before each snapshot and records their
+    /// length in it, so resuming can drop the rejects written after the snapshot.
+    pub fn with_rejects(mut self, rejects: SharedRejects) -> Self {
+        self.rejects = Some(rejects);
+        self
+    }
+    /// Saves a last snapshot at the end of the input, if anything was read at all.
+    pub fn finish(mut self) -> Result<(), anyhow::Error> {
+        if let Some(last) = self.last {
+            self.save(last)?;
+        }
+        Ok(())
+    }
+    fn save(&mut self, position: Position) -> Result<(), anyhow::Error> {
+        let rejects = match &self.rejects {
+            Some(rejects) => Some(
+                rejects
+                    .lock()
+                    .map_err(|_| anyhow::anyhow!("rejects poisoned"))?
+                    .sync()?,
+            ),
+            None => None,
+        };
+        self.accounts
+            .save_snapshot_file(&self.path, position, rejects)
+    }
+}
+
+impl TransactionUser for Snapshotter<'_> {
+    fn use_tx(&mut self, tx: TypedTransaction) -> Result<(), Error> {
+        self.accounts.use_tx(tx)
+    }
+    fn use_tx_at(
+        &mut self,
+        tx: TypedTransaction,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), Error> {
+        self.accounts.use_tx_at(tx, timestamp)
+    }
+    fn checkpoint(&mut self, next: Position) -> Result<(), anyhow::Error> {
+        self.rows += 1;
+        self.last = Some(next);
+        if self.rows.is_multiple_of(self.every) {
+            self.save(next)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compute::Accounts,
+        data::{amount, InputSource, Position, TypedTransaction},
+        read::{read_transactions, resume_transactions, ReadOptions, TransactionUser},
+        testing::TRANSACTIONS_CSV,
+    };
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let mut accounts = Accounts::new().with_ledger();
+        read_transactions(
+            TRANSACTIONS_CSV,
+            InputSource::User,
+            &mut accounts,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        // evicted ids are remembered too
+        let mut accounts = accounts.with_dispute_window(100);
+        let deposit = TypedTransaction::Deposit {
+            client: 3,
+            id: 5,
+            amount: amount!(1),
+        };
+        accounts.use_tx_at(deposit, Some(1000)).unwrap();
+        assert_eq!(accounts.evict_expired(2000), Ok(1));
+        let mut snapshot = Vec::new();
+        accounts
+            .save_snapshot(&mut snapshot, Position::new(42, 7), Some(3))
+            .unwrap();
+        let (restored, position, rejects) = Accounts::load_snapshot(&snapshot[..]).unwrap();
+        assert_eq!(restored, accounts);
+        assert_eq!(position, Position::new(42, 7));
+        assert_eq!(rejects, Some(3));
+    }
+    #[test]
+    fn snapshot_resume() {
+        let mut expected = Accounts::new();
+        read_transactions(
+            TRANSACTIONS_CSV,
+            InputSource::User,
+            &mut expected,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        // stop after the dispute, on line 4
+        let half = TRANSACTIONS_CSV
+            .split_inclusive(|&b| b == b'\n')
+            .take(4)
+            .flatten()
+            .copied()
+            .collect::<Vec<_>>();
+        let mut accounts = Accounts::new();
+        read_transactions(
+            &half[..],
+            InputSource::User,
+            &mut accounts,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        let mut snapshot = Vec::new();
+        accounts
+            .save_snapshot(&mut snapshot, Position::new(half.len() as u64, 5), None)
+            .unwrap();
+        let (mut accounts, position, _) = Accounts::load_snapshot(&snapshot[..]).unwrap();
+        let input = std::io::Cursor::new(TRANSACTIONS_CSV);
+        resume_transactions(
+            input,
+            position,
+            InputSource::User,
+            &mut accounts,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(accounts, expected);
+    }
+}
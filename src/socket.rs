@@ -0,0 +1,351 @@
+use crate::{
+    compute::Accounts,
+    data::{Account, ClientId, Error, InputSource, Transaction, TypedTransaction},
+    read::{malformed_message, parse_line, TransactionUser},
+    store::{AccountStore, MemoryTxStore, TxStore},
+    write::{write_accounts, AccountOrder},
+};
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+/// Line protocol over `Accounts`, for feeds which can't wait for a batch file. Each
+/// connection sends CSV, line by line: a header first (as with `read_transactions`), then
+/// a transaction per row. Every row is answered by a line, `ACK` if it was accepted, or
+/// `ERR` followed by the error code and message (`malformed_row` for a row which can't
+/// be read). The header isn't answered, unless it's malformed (e.g. without a `type`
+/// column): then it's answered like a row, and the next line is taken as the header.
+/// A `DUMP` line is answered with the accounts as written by `write_accounts`, followed
+/// by an empty line. Lines longer than 64 KiB are answered with `malformed_row`,
+/// and skipped.
+///
+/// Every connection has its own thread, all of them feeding the same `Accounts` behind a
+/// `RwLock`, so rows are applied one at a time while dumps go in parallel.
+/// Longest line read by `LineServer`, so a peer can't make it buffer without limit
+const MAX_LINE: usize = 64 * 1024;
+
+pub struct LineServer<S = MemoryTxStore, A = BTreeMap<ClientId, Account>> {
+    accounts: RwLock<Accounts<S, A>>,
+    source: InputSource,
+}
+
+impl<S: TxStore, A: AccountStore> LineServer<S, A> {
+    pub fn new(accounts: Accounts<S, A>) -> Self {
+        Self {
+            accounts: RwLock::new(accounts),
+            source: InputSource::default(),
+        }
+    }
+    /// Where transactions are deemed to come from, e.g. to accept administrative ones
+    pub fn with_source(mut self, source: InputSource) -> Self {
+        self.source = source;
+        self
+    }
+    /// Gives back the accounts, once every connection is closed
+    pub fn into_accounts(self) -> Accounts<S, A> {
+        self.accounts
+            .into_inner()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    // Changes to accounts are only made once nothing can fail anymore (see `Accounts`), so
+    // a panicking thread can't have left them half-updated.
+    fn read(&self) -> RwLockReadGuard<'_, Accounts<S, A>> {
+        self.accounts
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    fn write(&self) -> RwLockWriteGuard<'_, Accounts<S, A>> {
+        self.accounts
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    fn apply(&self, headers: &csv::StringRecord, line: &str) -> Result<(), Error> {
+        let tx: Transaction = parse_line(line)
+            .and_then(|record| record.deserialize(Some(headers)))
+            .map_err(|e| Error::MalformedRow(malformed_message(&e)))?;
+        let timestamp = tx.timestamp();
+        self.source.check(&tx)?;
+        let tx = TypedTransaction::try_from(tx)?;
+        self.write().use_tx_at(tx, timestamp)
+    }
+    /// Handles a connection until the other end closes it
+    pub fn handle<R: BufRead, W: Write>(&self, mut reader: R, mut writer: W) -> anyhow::Result<()> {
+        let mut headers = None;
+        let mut bytes = Vec::new();
+        loop {
+            bytes.clear();
+            if reader
+                .by_ref()
+                .take(MAX_LINE as u64 + 1)
+                .read_until(b'\n', &mut bytes)?
+                == 0
+            {
+                return Ok(());
+            }
+            let reply = match std::str::from_utf8(&bytes).map(str::trim) {
+                _ if bytes.len() > MAX_LINE => {
+                    reader.skip_until(b'\n')?;
+                    let message = format!("line longer than {MAX_LINE} bytes");
+                    Some(Err(Error::MalformedRow(message)))
+                }
+                Ok("") => continue,
+                Ok("DUMP") => {
+                    write_accounts(&mut writer, &*self.read(), AccountOrder::Client)?;
+                    writeln!(writer)?;
+                    None
+                }
+                Ok(line) => match &headers {
+                    Some(headers) => Some(self.apply(headers, line)),
+                    None => match header(line) {
+                        Ok(header) => {
+                            headers = Some(header);
+                            None
+                        }
+                        Err(e) => Some(Err(e)),
+                    },
+                },
+                Err(e) => Some(Err(Error::MalformedRow(e.to_string()))),
+            };
+            match reply {
+                Some(Ok(())) => writeln!(writer, "ACK")?,
+                Some(Err(e)) => writeln!(writer, "ERR {} {e}", e.code())?,
+                None => {}
+            }
+            writer.flush()?;
+        }
+    }
+    /// Serves each connection in its own thread, `split` giving its reading and writing ends
+    fn run<T, R, W>(
+        &self,
+        incoming: impl Iterator<Item = std::io::Result<T>>,
+        split: impl Fn(T) -> std::io::Result<(R, W)> + Sync,
+    ) -> anyhow::Result<()>
+    where
+        S: Send + Sync,
+        A: Send + Sync,
+        T: Send,
+        R: BufRead,
+        W: Write,
+    {
+        std::thread::scope(|scope| {
+            for stream in incoming {
+                let stream = stream?;
+                let split = &split;
+                scope.spawn(move || {
+                    let result = split(stream)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|(reader, writer)| self.handle(reader, writer));
+                    if let Err(e) = result {
+                        eprintln!("Connection failed: {e}");
+                    }
+                });
+            }
+            Ok(())
+        })
+    }
+    /// Serves the connections to `listener`, forever
+    pub fn run_tcp(&self, listener: TcpListener) -> anyhow::Result<()>
+    where
+        S: Send + Sync,
+        A: Send + Sync,
+    {
+        self.run(listener.incoming(), |stream| {
+            Ok((BufReader::new(stream.try_clone()?), stream))
+        })
+    }
+    /// Serves the connections to `listener`, forever
+    #[cfg(unix)]
+    pub fn run_unix(&self, listener: std::os::unix::net::UnixListener) -> anyhow::Result<()>
+    where
+        S: Send + Sync,
+        A: Send + Sync,
+    {
+        self.run(listener.incoming(), |stream| {
+            Ok((BufReader::new(stream.try_clone()?), stream))
+        })
+    }
+}
+
+/// Reads a header line, which must at least name the columns every transaction has
+fn header(line: &str) -> Result<csv::StringRecord, Error> {
+    let header = parse_line(line).map_err(|e| Error::MalformedRow(e.to_string()))?;
+    let missing = ["type", "client", "tx"]
+        .into_iter()
+        .find(|&column| !header.iter().any(|field| field == column));
+    match missing {
+        Some(column) => Err(Error::MalformedRow(format!(
+            "header without a `{column}` column"
+        ))),
+        None => Ok(header),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LineServer;
+    use crate::{compute::Accounts, data::amount};
+    use std::io::{BufRead, BufReader, Write};
+
+    fn replies(server: &LineServer, input: &str) -> String {
+        let mut output = Vec::new();
+        server.handle(input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn line_protocol() {
+        let server = LineServer::new(Accounts::new());
+        let output = replies(
+            &server,
+            "type, client, tx, amount\r\n\
+             deposit, 1, 1, 2.5\r\n\
+             \r\n\
+             withdrawal, 1, 2, 3\r\n\
+             deposit, 1, 3, lots\r\n\
+             DUMP\r\n\
+             dispute, 1, 1,\r\n",
+        );
+        assert_eq!(
+            output,
+            "ACK\n\
+             ERR insufficient_funds Insufficient funds for operation (asked 3.0000 while 2.5000 available)\n\
+             ERR malformed_row Malformed row: invalid value: string \"lots\", \
+             expected a Decimal type representing a fixed-point number\n\
+             client,available,held,total,locked\n\
+             1,2.5000,0.0000,2.5000,false\n\
+             \n\
+             ACK\n"
+        );
+        let accounts = server.into_accounts();
+        assert_eq!(accounts.account(1).unwrap().held(), amount!(2.5));
+    }
+    #[test]
+    fn line_protocol_long_line() {
+        let server = LineServer::new(Accounts::new());
+        let input = format!(
+            "type, client, tx, amount\ndeposit, 1, 1, {}\ndeposit, 1, 2, 1\n",
+            "9".repeat(super::MAX_LINE)
+        );
+        assert_eq!(
+            replies(&server, &input),
+            format!(
+                "ERR malformed_row Malformed row: line longer than {} bytes\nACK\n",
+                super::MAX_LINE
+            )
+        );
+    }
+    #[test]
+    fn line_protocol_header() {
+        let server = LineServer::new(Accounts::new());
+        let mut output = Vec::new();
+        let input = b"deposit, 1, 1, 2.5\n\
+            type, \xff, tx\n\
+            type, client, tx, amount\n\
+            deposit, 1, 1, 2.5\n";
+        server.handle(&input[..], &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "ERR malformed_row Malformed row: header without a `type` column\n\
+             ERR malformed_row Malformed row: invalid utf-8 sequence of 1 bytes from index 6\n\
+             ACK\n"
+        );
+    }
+    /// Sends `input` over `stream`, and reads the replies until the server hangs up
+    fn exchange<S: std::io::Read + Write>(
+        mut stream: S,
+        input: &str,
+        shutdown: impl FnOnce(&S) -> std::io::Result<()>,
+    ) -> String {
+        stream.write_all(input.as_bytes()).unwrap();
+        shutdown(&stream).unwrap();
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+        output
+    }
+    const FEED: &str = "type,client,tx,amount\ndeposit,1,1,2.5\nwithdrawal,1,2,3\n";
+    const REPLIES: &str = "ACK\n\
+        ERR insufficient_funds Insufficient funds for operation (asked 3.0000 while 2.5000 available)\n";
+    #[test]
+    fn line_protocol_tcp() {
+        // the server runs forever, so it's left behind
+        let server: &'static LineServer = Box::leak(Box::new(LineServer::new(Accounts::new())));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || server.run_tcp(listener));
+        let connect = |input| {
+            let stream = std::net::TcpStream::connect(addr).unwrap();
+            exchange(stream, input, |stream| {
+                stream.shutdown(std::net::Shutdown::Write)
+            })
+        };
+        assert_eq!(connect(FEED), REPLIES);
+        // the next connections see the same accounts
+        assert_eq!(
+            connect("type,client,tx,amount\ndeposit,1,1,2.5\n"),
+            "ERR duplicate_transaction Duplicate transaction #1\n"
+        );
+        let output = connect("DUMP\n");
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n1,2.5000,0.0000,2.5000,false\n\n"
+        );
+    }
+    #[cfg(unix)]
+    #[test]
+    fn line_protocol_unix() {
+        use std::os::unix::net::{UnixListener, UnixStream};
+        let server: &'static LineServer = Box::leak(Box::new(LineServer::new(Accounts::new())));
+        let path = std::env::temp_dir().join(format!("ingest-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        std::thread::spawn(move || server.run_unix(listener));
+        let stream = UnixStream::connect(&path);
+        let _ = std::fs::remove_file(&path);
+        let output = exchange(stream.unwrap(), FEED, |stream| {
+            stream.shutdown(std::net::Shutdown::Write)
+        });
+        assert_eq!(output, REPLIES);
+    }
+    #[test]
+    fn line_protocol_connections() {
+        let server = LineServer::new(Accounts::new());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::scope(|scope| {
+            let server = &server;
+            scope.spawn(move || {
+                for stream in listener.incoming().take(4) {
+                    let stream = stream.unwrap();
+                    let reader = BufReader::new(stream.try_clone().unwrap());
+                    scope.spawn(move || server.handle(reader, stream).unwrap());
+                }
+            });
+            let feeds = (0..4u32).map(|client| {
+                scope.spawn(move || {
+                    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+                    writeln!(stream, "type,client,tx,amount").unwrap();
+                    for n in 0..100 {
+                        let id = client * 100 + n;
+                        writeln!(stream, "deposit,{client},{id},1").unwrap();
+                    }
+                    // ids are shared between clients
+                    writeln!(stream, "withdrawal,{client},0,1").unwrap();
+                    stream.shutdown(std::net::Shutdown::Write).unwrap();
+                    BufReader::new(stream)
+                        .lines()
+                        .filter(|line| line.as_ref().unwrap() == "ACK")
+                        .count()
+                })
+            });
+            let acks: usize = feeds.map(|feed| feed.join().unwrap()).sum();
+            assert_eq!(acks, 400);
+        });
+        let accounts = server.into_accounts();
+        for client in 0..4 {
+            assert_eq!(accounts.account(client).unwrap().available(), amount!(100));
+        }
+    }
+}
@@ -0,0 +1,462 @@
+use crate::{
+    compute::Accounts,
+    data::{Account, Amount, ClientId, Error, Position, RowError, Timestamp, TypedTransaction},
+    read::TransactionUser,
+    store::{failure, AccountStore, TxStore},
+};
+use rusqlite::{params, Connection, Row, Statement};
+use rust_decimal::Decimal;
+use std::path::Path;
+
+/// Table of the accounts, with the same columns as the CSV output. Amounts are stored as
+/// text, as SQLite has no decimal type and floating point would lose digits; cast them
+/// (e.g. `CAST(total AS REAL)`) for approximate arithmetic.
+const ACCOUNTS_SCHEMA: &str = "
+    CREATE TABLE accounts (
+        client    INTEGER PRIMARY KEY,
+        available TEXT NOT NULL,
+        held      TEXT NOT NULL,
+        total     TEXT NOT NULL,
+        locked    INTEGER NOT NULL,
+        closed    INTEGER NOT NULL
+    );
+";
+
+/// Table of the transactions logged by a `SqliteTransactionLog`, in the order they were
+/// processed. `outcome` is `accepted` or the code of the error which rejected the
+/// transaction (see `Error::code`), `error` being the error message. Rows which failed
+/// before being applied are logged too: those which couldn't be read have no type, client
+/// or tx, but their `content` as read.
+const TRANSACTIONS_SCHEMA: &str = "
+    CREATE TABLE transactions (
+        sequence  INTEGER PRIMARY KEY,
+        type      TEXT,
+        client    INTEGER,
+        tx        INTEGER,
+        amount    TEXT,
+        operator  TEXT,
+        reason    TEXT,
+        timestamp INTEGER,
+        outcome   TEXT NOT NULL,
+        error     TEXT,
+        content   TEXT
+    );
+    CREATE INDEX transactions_client ON transactions (client);
+    CREATE INDEX transactions_tx ON transactions (tx);
+";
+
+const INSERT_ACCOUNT: &str = "INSERT OR REPLACE INTO accounts VALUES (?1, ?2, ?3, ?4, ?5, ?6)";
+
+fn insert_account(statement: &mut Statement, account: &Account) -> Result<(), rusqlite::Error> {
+    statement.execute(params![
+        account.client,
+        Decimal::from(account.available).to_string(),
+        Decimal::from(account.held).to_string(),
+        account.total().to_string(),
+        account.locked,
+        account.closed,
+    ])?;
+    Ok(())
+}
+
+/// `AccountStore` keeping the accounts in an SQLite database, so other tools can query
+/// them while they're processed (see `ACCOUNTS_SCHEMA` for the table). Each change is its
+/// own SQLite transaction, which is much slower than keeping them in memory.
+#[derive(Debug)]
+pub struct SqliteAccountStore {
+    connection: Connection,
+}
+
+impl SqliteAccountStore {
+    /// Creates an empty store in the database at `path`, replacing any existing accounts
+    pub fn create(path: &Path) -> Result<Self, rusqlite::Error> {
+        let connection = Connection::open(path)?;
+        // durable enough with the write-ahead log, without syncing on every change
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        connection.execute_batch("DROP TABLE IF EXISTS accounts;")?;
+        connection.execute_batch(ACCOUNTS_SCHEMA)?;
+        Ok(Self { connection })
+    }
+}
+
+fn amount(row: &Row, column: &str) -> Result<Amount, Error> {
+    let text: String = row.get(column).map_err(failure)?;
+    Amount::new(text.parse::<Decimal>().map_err(failure)?)
+}
+
+fn account(row: &Row) -> Result<Account, Error> {
+    Ok(Account {
+        client: row.get("client").map_err(failure)?,
+        available: amount(row, "available")?,
+        held: amount(row, "held")?,
+        locked: row.get("locked").map_err(failure)?,
+        closed: row.get("closed").map_err(failure)?,
+    })
+}
+
+impl AccountStore for SqliteAccountStore {
+    fn get(&self, client: ClientId) -> Result<Option<Account>, Error> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT * FROM accounts WHERE client = ?1")
+            .map_err(failure)?;
+        let mut rows = statement.query([client]).map_err(failure)?;
+        rows.next().map_err(failure)?.map(account).transpose()
+    }
+    fn upsert(&mut self, account: Account) -> Result<(), Error> {
+        let mut statement = self
+            .connection
+            .prepare_cached(INSERT_ACCOUNT)
+            .map_err(failure)?;
+        insert_account(&mut statement, &account).map_err(failure)
+    }
+    fn remove(&mut self, client: ClientId) -> Result<(), Error> {
+        let mut statement = self
+            .connection
+            .prepare_cached("DELETE FROM accounts WHERE client = ?1")
+            .map_err(failure)?;
+        statement.execute([client]).map_err(failure)?;
+        Ok(())
+    }
+    fn for_each(&self, f: &mut dyn FnMut(&Account)) -> Result<(), Error> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT * FROM accounts ORDER BY client")
+            .map_err(failure)?;
+        let mut rows = statement.query([]).map_err(failure)?;
+        while let Some(row) = rows.next().map_err(failure)? {
+            f(&account(row)?);
+        }
+        Ok(())
+    }
+}
+
+/// SQLite exporter for `Accounts`: writes them to the `accounts` table of the database at
+/// `path` (see `ACCOUNTS_SCHEMA`), replacing the accounts it may already have. Other tables
+/// are left alone, so it can go in the same database as a `SqliteTransactionLog`.
+pub fn write_accounts_sqlite<S: TxStore, A: AccountStore>(
+    path: &Path,
+    accounts: &Accounts<S, A>,
+) -> Result<(), anyhow::Error> {
+    // read them all first, in case they come from that very table
+    let mut rows = Vec::new();
+    accounts
+        .account_store()
+        .for_each(&mut |account| rows.push(account.clone()))?;
+    let mut connection = Connection::open(path)?;
+    let transaction = connection.transaction()?;
+    transaction.execute_batch("DROP TABLE IF EXISTS accounts;")?;
+    transaction.execute_batch(ACCOUNTS_SCHEMA)?;
+    {
+        let mut statement = transaction.prepare(INSERT_ACCOUNT)?;
+        for account in &rows {
+            insert_account(&mut statement, account)?;
+        }
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+/// `TransactionUser` logging every transaction given to another one (usually `Accounts`)
+/// to the `transactions` table of an SQLite database, along with its outcome (see
+/// `TRANSACTIONS_SCHEMA`). Everything is written in a single SQLite transaction committed
+/// by `finish`, as committing each row would be way too slow: `finish` should be called
+/// even when reading failed, to keep the rows logged before.
+///
+/// Logging a transaction happens after it's applied, so a logging failure doesn't reject
+/// it; it's reported on the next `checkpoint` instead, which stops the reading.
+pub struct SqliteTransactionLog<U: TransactionUser> {
+    user: U,
+    connection: Connection,
+    sequence: u64,
+    failure: Option<rusqlite::Error>,
+}
+
+impl<U: TransactionUser> SqliteTransactionLog<U> {
+    /// Starts logging the transactions given to `user` to the database at `path`,
+    /// replacing the transactions it may already have
+    pub fn create(path: &Path, user: U) -> Result<Self, rusqlite::Error> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch("DROP TABLE IF EXISTS transactions;")?;
+        connection.execute_batch(TRANSACTIONS_SCHEMA)?;
+        connection.execute_batch("BEGIN;")?;
+        Ok(Self {
+            user,
+            connection,
+            sequence: 0,
+            failure: None,
+        })
+    }
+    /// Commits the log and gives back the inner `TransactionUser`
+    pub fn finish(self) -> Result<U, anyhow::Error> {
+        if let Some(failure) = self.failure {
+            return Err(failure.into());
+        }
+        self.connection.execute_batch("COMMIT;")?;
+        Ok(self.user)
+    }
+    fn log(
+        &mut self,
+        tx: &TypedTransaction,
+        timestamp: Option<Timestamp>,
+        result: &Result<(), Error>,
+    ) -> Result<(), rusqlite::Error> {
+        let (amount, admin) = match tx {
+            TypedTransaction::Deposit { amount, .. }
+            | TypedTransaction::Withdrawal { amount, .. } => (Some(amount.to_string()), None),
+            TypedTransaction::Lock { admin, .. }
+            | TypedTransaction::Unlock { admin, .. }
+            | TypedTransaction::Close { admin, .. } => (None, Some(admin)),
+            _ => (None, None),
+        };
+        let (outcome, error) = match result {
+            Ok(()) => ("accepted", None),
+            Err(e) => (e.code(), Some(e.to_string())),
+        };
+        self.sequence += 1;
+        let mut statement = self.connection.prepare_cached(
+            "INSERT INTO transactions VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, NULL)",
+        )?;
+        statement.execute(params![
+            self.sequence,
+            tx.txtype().name(),
+            tx.client(),
+            tx.id(),
+            amount,
+            admin.map(|admin| admin.operator()),
+            admin.and_then(|admin| admin.reason()),
+            timestamp,
+            outcome,
+            error,
+        ])?;
+        Ok(())
+    }
+}
+
+impl<U: TransactionUser> TransactionUser for SqliteTransactionLog<U> {
+    fn use_tx(&mut self, tx: TypedTransaction) -> Result<(), Error> {
+        self.use_tx_at(tx, None)
+    }
+    fn use_tx_at(
+        &mut self,
+        tx: TypedTransaction,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), Error> {
+        let result = self.user.use_tx_at(tx.clone(), timestamp);
+        if self.failure.is_none() {
+            if let Err(e) = self.log(&tx, timestamp, &result) {
+                self.failure = Some(e);
+            }
+        }
+        result
+    }
+    /// A broken log stops the reading altogether
+    fn checkpoint(&mut self, next: Position) -> Result<(), anyhow::Error> {
+        if let Some(failure) = self.failure.take() {
+            return Err(failure.into());
+        }
+        self.user.checkpoint(next)
+    }
+    fn rejected(&mut self, row: &RowError) -> Result<(), anyhow::Error> {
+        if let Some(failure) = self.failure.take() {
+            return Err(failure.into());
+        }
+        self.sequence += 1;
+        let mut statement = self.connection.prepare_cached(
+            "INSERT INTO transactions (sequence, type, client, tx, outcome, error, content) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?;
+        statement.execute(params![
+            self.sequence,
+            row.txtype().map(|txtype| txtype.name()),
+            row.client(),
+            row.id(),
+            row.error().code(),
+            row.error().to_string(),
+            row.content(),
+        ])?;
+        self.user.rejected(row)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_accounts_sqlite, SqliteAccountStore, SqliteTransactionLog};
+    use crate::{
+        compute::Accounts,
+        data::{amount, Account, InputSource},
+        read::{read_transactions, ErrorPolicy, ReadOptions},
+        store::{AccountStore, MemoryTxStore},
+        testing::{TempPath, TRANSACTIONS_CSV},
+        write::{write_accounts, AccountOrder},
+    };
+
+    #[test]
+    fn sqlite_store() {
+        let path = TempPath::new("sqlite-store.db");
+        let mut store = SqliteAccountStore::create(&path.0).unwrap();
+        assert_eq!(store.get(1).unwrap(), None);
+        let account = Account {
+            client: 1,
+            available: amount!(12345.6789),
+            held: amount!(0.0001),
+            locked: true,
+            closed: false,
+        };
+        store.upsert(account.clone()).unwrap();
+        assert_eq!(store.get(1).unwrap(), Some(account));
+        store.remove(1).unwrap();
+        assert_eq!(store.get(1).unwrap(), None);
+        drop(store);
+    }
+    #[test]
+    fn sqlite_store_accounts() {
+        let path = TempPath::new("sqlite-accounts.db");
+        let mut expected = Accounts::new();
+        read_transactions(
+            TRANSACTIONS_CSV,
+            InputSource::User,
+            &mut expected,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        let store = SqliteAccountStore::create(&path.0).unwrap();
+        let mut accounts = Accounts::with_stores(MemoryTxStore::default(), store);
+        read_transactions(
+            TRANSACTIONS_CSV,
+            InputSource::User,
+            &mut accounts,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        let mut output = Vec::new();
+        write_accounts(&mut output, &accounts, AccountOrder::Total).unwrap();
+        let mut expected_output = Vec::new();
+        write_accounts(&mut expected_output, &expected, AccountOrder::Total).unwrap();
+        assert_eq!(output, expected_output);
+        drop(accounts);
+    }
+    #[test]
+    fn sqlite_output() {
+        let path = TempPath::new("sqlite-output.db");
+        let mut accounts = Accounts::new();
+        let mut log = SqliteTransactionLog::create(&path.0, &mut accounts).unwrap();
+        read_transactions(
+            TRANSACTIONS_CSV,
+            InputSource::User,
+            &mut log,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        log.finish().unwrap();
+        write_accounts_sqlite(&path.0, &accounts).unwrap();
+        let connection = rusqlite::Connection::open(&path.0).unwrap();
+        let total: String = connection
+            .query_row("SELECT total FROM accounts WHERE client = 2", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(total, "0.5000");
+        let mut statement = connection
+            .prepare("SELECT type, tx, amount, outcome FROM transactions ORDER BY sequence")
+            .unwrap();
+        let rows = statement
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, u32>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, String>(3)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(rows.len(), 6);
+        assert_eq!(
+            rows[3],
+            (
+                "withdrawal".into(),
+                3,
+                Some("5.0000".into()),
+                "insufficient_funds".into()
+            )
+        );
+        assert_eq!(rows[5], ("chargeback".into(), 1, None, "accepted".into()));
+        let indices: u32 = connection
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND tbl_name = 'transactions'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(indices, 2);
+        drop(statement);
+        drop(connection);
+    }
+    #[test]
+    fn sqlite_output_rejected() {
+        let path = TempPath::new("sqlite-rejected.db");
+        let transactions_csv = b"\
+type,       client, tx, amount
+deposit,    1,      1,  1.0
+lock,       1,      2,
+deposit,    1,      3,  lots
+deposit,    1,      4,
+deposit,    1,      5,  -1.0
+deposit,    1,      6,  1.0
+";
+        let mut accounts = Accounts::new();
+        let mut log = SqliteTransactionLog::create(&path.0, &mut accounts).unwrap();
+        let policy = ErrorPolicy::AbortAfter(4);
+        read_transactions(
+            &transactions_csv[..],
+            InputSource::User,
+            &mut log,
+            ReadOptions::default().with_policy(policy),
+        )
+        .unwrap_err();
+        // what was logged before stopping is still committed
+        log.finish().unwrap();
+        let connection = rusqlite::Connection::open(&path.0).unwrap();
+        let mut statement = connection
+            .prepare("SELECT type, tx, outcome, content FROM transactions ORDER BY sequence")
+            .unwrap();
+        let rows = statement
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, Option<String>>(0)?,
+                    row.get::<_, Option<u32>>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let content = "deposit,    1,      3,  lots";
+        assert_eq!(
+            rows,
+            [
+                (Some("deposit".into()), Some(1), "accepted".into(), None),
+                (Some("lock".into()), Some(2), "admin_only".into(), None),
+                (None, None, "malformed_row".into(), Some(content.into())),
+                (
+                    Some("deposit".into()),
+                    Some(4),
+                    "missing_amount".into(),
+                    None
+                ),
+                (
+                    Some("deposit".into()),
+                    Some(5),
+                    "negative_amount".into(),
+                    None
+                ),
+            ]
+        );
+        drop(statement);
+        drop(connection);
+    }
+}
@@ -0,0 +1,297 @@
+use crate::{
+    compute::Accounts,
+    data::{Account, Amount, ClientId, Error, Position, Timestamp, TxId, TxType, TypedTransaction},
+    read::TransactionUser,
+};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// An accepted operation, with the state of the account right after it
+#[derive(Debug, Clone)]
+struct Operation {
+    tx: TxId,
+    txtype: TxType,
+    amount: Option<Amount>,
+    account: Account,
+}
+
+#[derive(Debug, Clone)]
+struct ClientStatement {
+    opening: Account,
+    operations: Vec<Operation>,
+}
+
+/// What a statement row is about: a balance, or an operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub(crate) enum Entry {
+    Balance(Balance),
+    Operation(TxType),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Balance {
+    Opening,
+    Closing,
+}
+
+/// A row of a statement, as written out; `tx` and `amount` are empty for the balances
+/// and for administrative operations.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct StatementRow {
+    pub client: ClientId,
+    pub entry: Entry,
+    pub tx: Option<TxId>,
+    pub amount: Option<Amount>,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+impl StatementRow {
+    fn new(entry: Entry, tx: Option<TxId>, amount: Option<Amount>, account: &Account) -> Self {
+        Self {
+            client: account.client,
+            entry,
+            tx,
+            amount,
+            available: account.available,
+            held: account.held,
+            total: account.total(),
+            locked: account.locked,
+        }
+    }
+}
+
+/// `TransactionUser` applying transactions to `Accounts`, and recording the operations
+/// accepted for a client (or all of them) to make their statement: opening balance, each
+/// operation with the running balances, and closing balance.
+///
+/// The opening balance is the account as it was before the first operation recorded, so
+/// it's only non-zero when resuming from a snapshot. The amount of a dispute, resolve or
+/// chargeback is the one of the disputed transaction.
+pub struct Statement<'a> {
+    accounts: &'a mut Accounts,
+    client: Option<ClientId>,
+    statements: BTreeMap<ClientId, ClientStatement>,
+}
+
+impl<'a> Statement<'a> {
+    /// Statement of `client`, or of every client if `None`
+    pub fn new(accounts: &'a mut Accounts, client: Option<ClientId>) -> Self {
+        Self {
+            accounts,
+            client,
+            statements: BTreeMap::new(),
+        }
+    }
+    /// Rows of the statements, ordered by client id
+    pub(crate) fn rows(&self) -> Vec<StatementRow> {
+        let mut rows = Vec::new();
+        let mut push = |opening: &Account, operations: &[Operation], closing: &Account| {
+            rows.push(StatementRow::new(
+                Entry::Balance(Balance::Opening),
+                None,
+                None,
+                opening,
+            ));
+            for op in operations {
+                let entry = Entry::Operation(op.txtype);
+                rows.push(StatementRow::new(
+                    entry,
+                    Some(op.tx),
+                    op.amount,
+                    &op.account,
+                ));
+            }
+            rows.push(StatementRow::new(
+                Entry::Balance(Balance::Closing),
+                None,
+                None,
+                closing,
+            ));
+        };
+        for (client, statement) in &self.statements {
+            let closing = self.accounts.account(*client).unwrap_or(&statement.opening);
+            push(&statement.opening, &statement.operations, closing);
+        }
+        // nothing happened to the client, but they may have had an account already
+        if let Some(client) = self.client {
+            if !self.statements.contains_key(&client) {
+                if let Some(account) = self.accounts.account(client) {
+                    push(account, &[], account);
+                }
+            }
+        }
+        rows
+    }
+}
+
+impl TransactionUser for Statement<'_> {
+    fn use_tx(&mut self, tx: TypedTransaction) -> Result<(), Error> {
+        self.use_tx_at(tx, None)
+    }
+    fn use_tx_at(
+        &mut self,
+        tx: TypedTransaction,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), Error> {
+        let client = tx.client();
+        if self.client.is_some_and(|wanted| wanted != client) {
+            return self.accounts.use_tx_at(tx, timestamp);
+        }
+        let (id, txtype) = (tx.id(), tx.txtype());
+        let amount = match tx {
+            TypedTransaction::Deposit { amount, .. }
+            | TypedTransaction::Withdrawal { amount, .. } => Some(amount),
+            _ => None,
+        };
+        let before = self.accounts.account(client).cloned().unwrap_or(Account {
+            client,
+            ..Account::default()
+        });
+        self.accounts.use_tx_at(tx, timestamp)?;
+        let after = self
+            .accounts
+            .account(client)
+            .cloned()
+            .expect("accepted transaction without an account");
+        let amount = match txtype {
+            TxType::Dispute | TxType::Resolve | TxType::Chargeback => {
+                let moved = Decimal::from(after.held) - Decimal::from(before.held);
+                Amount::new(moved.abs()).ok()
+            }
+            _ => amount,
+        };
+        self.statements
+            .entry(client)
+            .or_insert_with(|| ClientStatement {
+                opening: before,
+                operations: Vec::new(),
+            })
+            .operations
+            .push(Operation {
+                tx: id,
+                txtype,
+                amount,
+                account: after,
+            });
+        Ok(())
+    }
+    fn checkpoint(&mut self, next: Position) -> Result<(), anyhow::Error> {
+        self.accounts.checkpoint(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Statement;
+    use crate::{
+        compute::Accounts,
+        data::{Format, InputSource, Position},
+        read::{read_transactions, resume_transactions, ReadOptions},
+        testing::TRANSACTIONS_CSV,
+        write::write_statement,
+    };
+
+    fn statement(accounts: &mut Accounts, client: Option<u16>) -> String {
+        let mut statement = Statement::new(accounts, client);
+        read_transactions(
+            TRANSACTIONS_CSV,
+            InputSource::User,
+            &mut statement,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        let mut output = Vec::new();
+        write_statement(&mut output, &statement, Format::Csv).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn statement_all() {
+        let mut accounts = Accounts::new();
+        assert_eq!(
+            statement(&mut accounts, None),
+            "\
+client,entry,tx,amount,available,held,total,locked
+1,opening,,,0.0000,0.0000,0.0000,false
+1,deposit,1,1.0000,1.0000,0.0000,1.0000,false
+1,dispute,1,1.0000,0.0000,1.0000,1.0000,false
+1,chargeback,1,1.0000,0.0000,0.0000,0.0000,true
+1,closing,,,0.0000,0.0000,0.0000,true
+2,opening,,,0.0000,0.0000,0.0000,false
+2,deposit,2,2.0000,2.0000,0.0000,2.0000,false
+2,withdrawal,4,1.5000,0.5000,0.0000,0.5000,false
+2,closing,,,0.5000,0.0000,0.5000,false
+"
+        );
+        let mut expected = Accounts::new();
+        read_transactions(
+            TRANSACTIONS_CSV,
+            InputSource::User,
+            &mut expected,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(accounts, expected);
+    }
+    #[test]
+    fn statement_client() {
+        let mut accounts = Accounts::new();
+        assert_eq!(
+            statement(&mut accounts, Some(2)),
+            "\
+client,entry,tx,amount,available,held,total,locked
+2,opening,,,0.0000,0.0000,0.0000,false
+2,deposit,2,2.0000,2.0000,0.0000,2.0000,false
+2,withdrawal,4,1.5000,0.5000,0.0000,0.5000,false
+2,closing,,,0.5000,0.0000,0.5000,false
+"
+        );
+        // unknown client, just like `write_accounts` without accounts
+        assert_eq!(statement(&mut Accounts::new(), Some(3)), "");
+    }
+    #[test]
+    fn statement_opening() {
+        let half = TRANSACTIONS_CSV
+            .split_inclusive(|&b| b == b'\n')
+            .take(3)
+            .flatten()
+            .copied()
+            .collect::<Vec<_>>();
+        let mut accounts = Accounts::new();
+        read_transactions(
+            &half[..],
+            InputSource::User,
+            &mut accounts,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        let mut statement = Statement::new(&mut accounts, Some(2));
+        let input = std::io::Cursor::new(TRANSACTIONS_CSV);
+        let start = Position::new(half.len() as u64, 4);
+        resume_transactions(
+            input,
+            start,
+            InputSource::User,
+            &mut statement,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        let mut output = Vec::new();
+        write_statement(&mut output, &statement, Format::Csv).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+client,entry,tx,amount,available,held,total,locked
+2,opening,,,2.0000,0.0000,2.0000,false
+2,withdrawal,4,1.5000,0.5000,0.0000,0.5000,false
+2,closing,,,0.5000,0.0000,0.5000,false
+"
+        );
+    }
+}
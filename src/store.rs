@@ -0,0 +1,408 @@
+use crate::data::{Account, Amount, ClientId, Error, StoredTx, Timestamp, TxId, TxState, TxType};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// Where `Accounts` keeps the deposits and withdrawals it accepted, so they can be disputed
+/// later. A store failure rejects the transaction being applied, leaving `Accounts`
+/// untouched.
+pub trait TxStore {
+    /// The transaction stored with this id, if any
+    fn get(&self, id: TxId) -> Result<Option<StoredTx>, Error>;
+    /// Stores a transaction, replacing the one with the same id if any
+    fn put(&mut self, id: TxId, tx: StoredTx) -> Result<(), Error>;
+    /// Removes the transactions for which `keep` is false, returning how many were
+    fn retain(&mut self, keep: &mut dyn FnMut(TxId, &StoredTx) -> bool) -> Result<usize, Error>;
+}
+
+/// Where `Accounts` keeps the accounts. Like with a `TxStore`, a failure rejects the
+/// transaction being applied.
+pub trait AccountStore {
+    /// The account of this client, if any
+    fn get(&self, client: ClientId) -> Result<Option<Account>, Error>;
+    /// Stores an account, replacing the one of the same client if any
+    fn upsert(&mut self, account: Account) -> Result<(), Error>;
+    /// Removes the account of this client, if any
+    fn remove(&mut self, client: ClientId) -> Result<(), Error>;
+    /// Calls `f` on every account, ordered by client id
+    fn for_each(&self, f: &mut dyn FnMut(&Account)) -> Result<(), Error>;
+}
+
+/// So the store can be picked at runtime
+impl<T: TxStore + ?Sized> TxStore for Box<T> {
+    fn get(&self, id: TxId) -> Result<Option<StoredTx>, Error> {
+        (**self).get(id)
+    }
+    fn put(&mut self, id: TxId, tx: StoredTx) -> Result<(), Error> {
+        (**self).put(id, tx)
+    }
+    fn retain(&mut self, keep: &mut dyn FnMut(TxId, &StoredTx) -> bool) -> Result<usize, Error> {
+        (**self).retain(keep)
+    }
+}
+
+/// So the store can be picked at runtime
+impl<T: AccountStore + ?Sized> AccountStore for Box<T> {
+    fn get(&self, client: ClientId) -> Result<Option<Account>, Error> {
+        (**self).get(client)
+    }
+    fn upsert(&mut self, account: Account) -> Result<(), Error> {
+        (**self).upsert(account)
+    }
+    fn remove(&mut self, client: ClientId) -> Result<(), Error> {
+        (**self).remove(client)
+    }
+    fn for_each(&self, f: &mut dyn FnMut(&Account)) -> Result<(), Error> {
+        (**self).for_each(f)
+    }
+}
+
+/// Default `AccountStore`, keeping everything in memory
+impl AccountStore for BTreeMap<ClientId, Account> {
+    fn get(&self, client: ClientId) -> Result<Option<Account>, Error> {
+        Ok(BTreeMap::get(self, &client).cloned())
+    }
+    fn upsert(&mut self, account: Account) -> Result<(), Error> {
+        self.insert(account.client, account);
+        Ok(())
+    }
+    fn remove(&mut self, client: ClientId) -> Result<(), Error> {
+        BTreeMap::remove(self, &client);
+        Ok(())
+    }
+    fn for_each(&self, f: &mut dyn FnMut(&Account)) -> Result<(), Error> {
+        self.values().for_each(f);
+        Ok(())
+    }
+}
+
+/// Default `TxStore`, keeping everything in memory
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MemoryTxStore {
+    pub(crate) transactions: HashMap<TxId, StoredTx>,
+}
+
+impl TxStore for MemoryTxStore {
+    fn get(&self, id: TxId) -> Result<Option<StoredTx>, Error> {
+        Ok(self.transactions.get(&id).copied())
+    }
+    fn put(&mut self, id: TxId, tx: StoredTx) -> Result<(), Error> {
+        self.transactions.insert(id, tx);
+        Ok(())
+    }
+    fn retain(&mut self, keep: &mut dyn FnMut(TxId, &StoredTx) -> bool) -> Result<usize, Error> {
+        let before = self.transactions.len();
+        self.transactions.retain(|&id, tx| keep(id, tx));
+        Ok(before - self.transactions.len())
+    }
+}
+
+/// Ids in a chunk of a `TxIdSet`
+const CHUNK_IDS: TxId = 1 << 16;
+
+/// Compact set of transaction ids: a bitmap in chunks of 65536 ids (8 KiB), only allocated
+/// for the ranges holding some. Stored as inclusive ranges of ids.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "Vec<(TxId, TxId)>", into = "Vec<(TxId, TxId)>")]
+pub(crate) struct TxIdSet {
+    chunks: BTreeMap<TxId, Box<[u64; (CHUNK_IDS / 64) as usize]>>,
+}
+
+impl TxIdSet {
+    pub(crate) fn insert(&mut self, id: TxId) {
+        let chunk = self
+            .chunks
+            .entry(id / CHUNK_IDS)
+            .or_insert_with(|| Box::new([0; (CHUNK_IDS / 64) as usize]));
+        let bit = id % CHUNK_IDS;
+        chunk[(bit / 64) as usize] |= 1 << (bit % 64);
+    }
+    pub(crate) fn contains(&self, id: TxId) -> bool {
+        let bit = id % CHUNK_IDS;
+        self.chunks
+            .get(&(id / CHUNK_IDS))
+            .is_some_and(|chunk| chunk[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+    /// Every id in the set, in order
+    fn ids(&self) -> impl Iterator<Item = TxId> + '_ {
+        self.chunks.iter().flat_map(|(&chunk, words)| {
+            words.iter().enumerate().flat_map(move |(word, &bits)| {
+                (0..64)
+                    .filter(move |bit| bits & (1 << bit) != 0)
+                    .map(move |bit| chunk * CHUNK_IDS + word as TxId * 64 + bit)
+            })
+        })
+    }
+}
+
+impl From<TxIdSet> for Vec<(TxId, TxId)> {
+    fn from(set: TxIdSet) -> Self {
+        let mut ranges: Vec<(TxId, TxId)> = Vec::new();
+        for id in set.ids() {
+            match ranges.last_mut() {
+                Some((_, last)) if *last + 1 == id => *last = id,
+                _ => ranges.push((id, id)),
+            }
+        }
+        ranges
+    }
+}
+
+impl From<Vec<(TxId, TxId)>> for TxIdSet {
+    fn from(ranges: Vec<(TxId, TxId)>) -> Self {
+        let mut set = TxIdSet::default();
+        for (first, last) in ranges {
+            (first..=last).for_each(|id| set.insert(id));
+        }
+        set
+    }
+}
+
+/// Size of a record in a `DiskTxStore`: presence, type, state, presence of the timestamp,
+/// client, padding, the 16 bytes of the amount's `Decimal`, and the timestamp.
+const RECORD_SIZE: u64 = 32;
+
+/// `TxStore` for inputs too big for memory: the transactions are kept in a file, as
+/// fixed-size records at the offset given by their id. The file is sparse, so it only
+/// takes the room of the pages actually written to, and lookups are a single read (most
+/// often from the OS page cache).
+#[derive(Debug)]
+pub struct DiskTxStore {
+    file: File,
+}
+
+impl DiskTxStore {
+    /// Creates an empty store at `path`, replacing any existing file
+    pub fn create(path: &Path) -> Result<Self, std::io::Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self { file })
+    }
+}
+
+pub(crate) fn failure(e: impl std::fmt::Display) -> Error {
+    Error::StoreFailure(e.to_string())
+}
+
+fn encode(tx: &StoredTx) -> [u8; RECORD_SIZE as usize] {
+    let mut record = [0; RECORD_SIZE as usize];
+    record[0] = 1;
+    record[1] = match tx.txtype {
+        TxType::Deposit => 0,
+        TxType::Withdrawal => 1,
+        TxType::Dispute => 2,
+        TxType::Resolve => 3,
+        TxType::Chargeback => 4,
+        TxType::Lock => 5,
+        TxType::Unlock => 6,
+        TxType::Close => 7,
+    };
+    record[2] = match tx.state {
+        TxState::Processed => 0,
+        TxState::Disputed => 1,
+        TxState::Resolved => 2,
+        TxState::ChargedBack => 3,
+    };
+    record[4..6].copy_from_slice(&tx.client.to_le_bytes());
+    record[8..24].copy_from_slice(&Decimal::from(tx.amount).serialize());
+    if let Some(timestamp) = tx.timestamp {
+        record[3] = 1;
+        record[24..].copy_from_slice(&timestamp.to_le_bytes());
+    }
+    record
+}
+
+fn decode(record: &[u8; RECORD_SIZE as usize]) -> Result<Option<StoredTx>, Error> {
+    if record[0] == 0 {
+        return Ok(None);
+    }
+    let txtype = match record[1] {
+        0 => TxType::Deposit,
+        1 => TxType::Withdrawal,
+        2 => TxType::Dispute,
+        3 => TxType::Resolve,
+        4 => TxType::Chargeback,
+        5 => TxType::Lock,
+        6 => TxType::Unlock,
+        7 => TxType::Close,
+        code => return Err(failure(format!("unknown transaction type {code}"))),
+    };
+    let state = match record[2] {
+        0 => TxState::Processed,
+        1 => TxState::Disputed,
+        2 => TxState::Resolved,
+        3 => TxState::ChargedBack,
+        code => return Err(failure(format!("unknown transaction state {code}"))),
+    };
+    let client = ClientId::from_le_bytes([record[4], record[5]]);
+    let mut amount = [0; 16];
+    amount.copy_from_slice(&record[8..24]);
+    let amount = Amount::new(Decimal::deserialize(amount))?;
+    let mut timestamp = [0; 8];
+    timestamp.copy_from_slice(&record[24..]);
+    let timestamp = (record[3] != 0).then_some(Timestamp::from_le_bytes(timestamp));
+    Ok(Some(
+        StoredTx::new(txtype, client, amount)
+            .with_state(state)
+            .with_timestamp(timestamp),
+    ))
+}
+
+impl TxStore for DiskTxStore {
+    fn get(&self, id: TxId) -> Result<Option<StoredTx>, Error> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(id as u64 * RECORD_SIZE))
+            .map_err(failure)?;
+        let mut record = [0; RECORD_SIZE as usize];
+        match file.read_exact(&mut record) {
+            Ok(()) => decode(&record),
+            // past the end of the file, so never written
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(failure(e)),
+        }
+    }
+    fn put(&mut self, id: TxId, tx: StoredTx) -> Result<(), Error> {
+        self.file
+            .seek(SeekFrom::Start(id as u64 * RECORD_SIZE))
+            .map_err(failure)?;
+        self.file.write_all(&encode(&tx)).map_err(failure)
+    }
+    /// Goes through the whole file, blanking the records of the transactions not kept
+    fn retain(&mut self, keep: &mut dyn FnMut(TxId, &StoredTx) -> bool) -> Result<usize, Error> {
+        self.file.seek(SeekFrom::Start(0)).map_err(failure)?;
+        let mut reader = std::io::BufReader::new(&self.file);
+        let mut record = [0; RECORD_SIZE as usize];
+        let mut offset = 0;
+        let mut blanked = Vec::new();
+        loop {
+            match reader.read_exact(&mut record) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(failure(e)),
+            }
+            if let Some(tx) = decode(&record)? {
+                if !keep((offset / RECORD_SIZE) as TxId, &tx) {
+                    blanked.push(offset);
+                }
+            }
+            offset += RECORD_SIZE;
+        }
+        drop(reader);
+        for &offset in &blanked {
+            self.file.seek(SeekFrom::Start(offset)).map_err(failure)?;
+            self.file
+                .write_all(&[0; RECORD_SIZE as usize])
+                .map_err(failure)?;
+        }
+        Ok(blanked.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DiskTxStore, TxIdSet, TxStore};
+    use crate::{
+        compute::Accounts,
+        data::{amount, InputSource, StoredTx, TxState, TxType},
+        read::{read_transactions, ReadOptions},
+        testing::TempPath,
+    };
+
+    /// Unlike the shared fixture, with the biggest transaction id
+    const TRANSACTIONS_CSV: &[u8] = b"\
+type,       client, tx,         amount
+deposit,    1,      1,          1.0
+deposit,    2,      4294967295, 2.0
+dispute,    1,      1,
+withdrawal, 2,      3,          5.0
+withdrawal, 2,      4,          1.5
+dispute,    2,      4294967295,
+resolve,    2,      4294967295,
+chargeback, 1,      1,
+";
+
+    #[test]
+    fn disk_store() {
+        let path = TempPath::new("store-records.db");
+        let mut store = DiskTxStore::create(&path.0).unwrap();
+        assert_eq!(store.get(12).unwrap(), None);
+        let tx = StoredTx::new(TxType::Withdrawal, 65535, amount!(12345.6789))
+            .with_state(TxState::ChargedBack);
+        store.put(12, tx).unwrap();
+        assert_eq!(store.get(12).unwrap(), Some(tx));
+        assert_eq!(store.get(11).unwrap(), None);
+        assert_eq!(store.get(13).unwrap(), None);
+    }
+    #[test]
+    fn disk_store_retain() {
+        let path = TempPath::new("store-retain.db");
+        let mut store = DiskTxStore::create(&path.0).unwrap();
+        let tx = |client| StoredTx::new(TxType::Deposit, client, amount!(1));
+        for id in 0..10 {
+            store.put(id * 3, tx(id as u16)).unwrap();
+        }
+        let blanked = store
+            .retain(&mut |id, tx| {
+                assert_eq!(id, tx.client() as u32 * 3);
+                tx.client() % 2 == 0
+            })
+            .unwrap();
+        assert_eq!(blanked, 5);
+        for id in 0..10 {
+            let expected = (id % 2 == 0).then(|| tx(id as u16));
+            assert_eq!(store.get(id * 3).unwrap(), expected);
+            assert_eq!(store.get(id * 3 + 1).unwrap(), None);
+        }
+        // blanked records stay blank
+        assert_eq!(store.retain(&mut |_, _| false).unwrap(), 5);
+    }
+    #[test]
+    fn tx_id_set() {
+        let mut set = TxIdSet::default();
+        for id in [0, 1, 2, 63, 64, 65535, 65536, 4294967295] {
+            set.insert(id);
+        }
+        assert!(set.contains(63) && set.contains(65536) && set.contains(4294967295));
+        assert!(!set.contains(3) && !set.contains(65537) && !set.contains(4294967294));
+        let ranges = Vec::from(set.clone());
+        assert_eq!(
+            ranges,
+            [(0, 2), (63, 64), (65535, 65536), (4294967295, 4294967295)]
+        );
+        assert_eq!(TxIdSet::from(ranges), set);
+    }
+    #[test]
+    fn disk_store_accounts() {
+        let path = TempPath::new("store-accounts.db");
+        let mut expected = Accounts::new();
+        read_transactions(
+            TRANSACTIONS_CSV,
+            InputSource::User,
+            &mut expected,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        let store = DiskTxStore::create(&path.0).unwrap();
+        let mut accounts = Accounts::with_tx_store(store);
+        read_transactions(
+            TRANSACTIONS_CSV,
+            InputSource::User,
+            &mut accounts,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(accounts.accounts, expected.accounts);
+        for (&id, &tx) in &expected.txset.transactions {
+            assert_eq!(accounts.txset.get(id).unwrap(), Some(tx));
+        }
+    }
+}
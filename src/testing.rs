@@ -0,0 +1,34 @@
+//! Fixtures shared by the tests of several modules
+
+use std::path::PathBuf;
+
+/// Transactions of two clients: a dispute ending in a chargeback, and a withdrawal
+/// rejected for lack of funds (line 5)
+pub(crate) const TRANSACTIONS_CSV: &[u8] = b"\
+type,       client, tx, amount
+deposit,    1,      1,  1.0
+deposit,    2,      2,  2.0
+dispute,    1,      1,
+withdrawal, 2,      3,  5.0
+withdrawal, 2,      4,  1.5
+chargeback, 1,      1,
+";
+
+/// Path of a temporary file, removed when dropped so a failed test doesn't leave it behind
+pub(crate) struct TempPath(pub(crate) PathBuf);
+
+impl TempPath {
+    /// The file is removed first, in case an earlier run left it
+    pub(crate) fn new(name: &str) -> Self {
+        let name = format!("payments-{}-{name}", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_file(&path);
+        Self(path)
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
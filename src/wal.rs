@@ -0,0 +1,391 @@
+use crate::{
+    data::{Error, Position, Timestamp, TxId, TypedTransaction},
+    read::TransactionUser,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Seek, Write},
+    path::Path,
+};
+
+/// A logged transaction, with its timestamp if it had one
+#[derive(Serialize, Deserialize)]
+struct WalEntry {
+    #[serde(flatten)]
+    tx: TypedTransaction,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<Timestamp>,
+}
+
+/// Line of the log: transactions are logged before being applied, so those which are then
+/// rejected are followed by a line cancelling them. When fed by a reader, the position of
+/// the input is logged after each row which logged a transaction, so a new run knows where
+/// to resume reading.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum WalLine {
+    Rejected { rejected: TxId },
+    Position { position: Position },
+    Entry(WalEntry),
+}
+
+/// `TransactionUser` making another one (usually `Accounts`) durable: every transaction
+/// is appended to a write-ahead log before being applied, and the log is replayed when
+/// opened. The log is a JSON Lines file of `TypedTransaction`s, those which were rejected
+/// being followed by a `{"rejected": id}` line so they're skipped on replay.
+///
+/// When fed by a reader, the log also holds the input position given to `checkpoint`
+/// (as `{"position": ...}` lines), and reading must resume from `position()` after a
+/// replay, so no row is applied twice. A transaction logged after the last position, its
+/// row not being over, is dropped on replay: it will be read again.
+///
+/// To be fast enough on big inputs, the log is only fsync'd every `batch` transactions, so
+/// on a power failure the last acknowledged transactions of a batch may be lost. If
+/// writing to the log fails, the transaction isn't applied; from there the log is
+/// considered broken and every other transaction is refused.
+pub struct Wal<U: TransactionUser> {
+    user: U,
+    log: BufWriter<File>,
+    batch: usize,
+    pending: usize,
+    failure: Option<std::io::Error>,
+    /// Transactions applied when replaying the log
+    replayed: usize,
+    /// Last position given to `checkpoint` (or replayed), and the last one logged
+    position: Option<Position>,
+    logged: Option<Position>,
+    /// Whether a transaction was logged since the last position
+    unpositioned: bool,
+}
+
+impl<U: TransactionUser> Wal<U> {
+    /// Opens (or creates) the log at `path`, replaying it into `user` which must be
+    /// configured (e.g. its `DisputePolicy`) just like when the log was written.
+    /// A last line torn by a crash while writing it is dropped.
+    pub fn open(path: &Path, mut user: U, batch: usize) -> Result<Self, anyhow::Error> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut reader = BufReader::new(&mut file);
+        let mut line = String::new();
+        // length of the whole lines, and of those up to the last position
+        let (mut valid, mut committed) = (0, 0);
+        let (mut position, mut replayed) = (None, 0);
+        // an entry is only applied once we know the next line doesn't cancel it
+        let mut last = None;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                break;
+            }
+            if !line.ends_with('\n') {
+                // torn write, the transaction was never acknowledged
+                break;
+            }
+            match serde_json::from_str(&line)? {
+                WalLine::Rejected { rejected } => match last.take() {
+                    Some(WalEntry { tx, .. }) if tx.id() == rejected => {}
+                    _ => anyhow::bail!(
+                        "can't replay write-ahead log: stray rejection of #{rejected}"
+                    ),
+                },
+                WalLine::Position { position: next } => {
+                    if let Some(entry) = last.take() {
+                        replay(&mut user, entry)?;
+                        replayed += 1;
+                    }
+                    position = Some(next);
+                    committed = valid + line.len() as u64;
+                }
+                WalLine::Entry(entry) => {
+                    // not fed by a reader, the previous one is over
+                    if let Some(entry) = last.replace(entry) {
+                        replay(&mut user, entry)?;
+                        replayed += 1;
+                        committed = valid;
+                    }
+                }
+            }
+            valid += line.len() as u64;
+        }
+        drop(reader);
+        if position.is_some() {
+            // the rows after the last position will be read again
+            valid = committed;
+            last = None;
+        }
+        if file.stream_position()? != valid {
+            file.set_len(valid)?;
+        }
+        let mut wal = Self {
+            user,
+            log: BufWriter::new(file),
+            batch: batch.max(1),
+            pending: 0,
+            failure: None,
+            replayed,
+            position,
+            logged: position,
+            unpositioned: false,
+        };
+        // the process may have stopped before applying the last entry, which then wasn't
+        // acknowledged either: it's applied now, and cancelled if it's rejected
+        if let Some(WalEntry { tx, timestamp }) = last {
+            let id = tx.id();
+            if wal.user.use_tx_at(tx, timestamp).is_err() {
+                wal.append(&WalLine::Rejected { rejected: id })?;
+                wal.sync()?;
+            } else {
+                wal.replayed += 1;
+            }
+        }
+        Ok(wal)
+    }
+    /// Where to resume reading the input the log was fed from, if it was fed by a reader
+    pub fn position(&self) -> Option<Position> {
+        self.position
+    }
+    /// How many transactions were applied when replaying the log
+    pub fn replayed(&self) -> usize {
+        self.replayed
+    }
+    /// Forces the log to disk
+    pub fn sync(&mut self) -> Result<(), anyhow::Error> {
+        if let Some(failure) = self.failure.take() {
+            return Err(failure.into());
+        }
+        self.log.flush()?;
+        self.log.get_ref().sync_data()?;
+        self.pending = 0;
+        Ok(())
+    }
+    /// Logs the last position, syncs the log one last time and gives back the inner
+    /// `TransactionUser`
+    pub fn finish(mut self) -> Result<U, anyhow::Error> {
+        if let Some(position) = self
+            .position
+            .filter(|&position| self.logged != Some(position))
+        {
+            self.write(&WalLine::Position { position })?;
+        }
+        self.sync()?;
+        Ok(self.user)
+    }
+    fn write(&mut self, line: &WalLine) -> Result<(), std::io::Error> {
+        serde_json::to_writer(&mut self.log, line)?;
+        writeln!(self.log)
+    }
+    fn append(&mut self, line: &WalLine) -> Result<(), std::io::Error> {
+        self.write(line)?;
+        self.pending += 1;
+        if self.pending >= self.batch {
+            self.log.flush()?;
+            self.log.get_ref().sync_data()?;
+            self.pending = 0;
+        }
+        Ok(())
+    }
+}
+
+fn replay<U: TransactionUser>(user: &mut U, entry: WalEntry) -> Result<(), anyhow::Error> {
+    user.use_tx_at(entry.tx, entry.timestamp)
+        .map_err(|e| anyhow::anyhow!("can't replay write-ahead log: {e}"))
+}
+
+impl<U: TransactionUser> TransactionUser for Wal<U> {
+    fn use_tx(&mut self, tx: TypedTransaction) -> Result<(), Error> {
+        self.use_tx_at(tx, None)
+    }
+    fn use_tx_at(
+        &mut self,
+        tx: TypedTransaction,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), Error> {
+        if self.failure.is_some() {
+            return Err(Error::WalUnavailable);
+        }
+        let id = tx.id();
+        if let Err(e) = self.append(&WalLine::Entry(WalEntry {
+            tx: tx.clone(),
+            timestamp,
+        })) {
+            self.failure = Some(e);
+            return Err(Error::WalUnavailable);
+        }
+        self.unpositioned = true;
+        let result = self.user.use_tx_at(tx, timestamp);
+        if result.is_err() {
+            if let Err(e) = self.append(&WalLine::Rejected { rejected: id }) {
+                self.failure = Some(e);
+            }
+        }
+        result
+    }
+    /// A broken log stops the reading altogether
+    fn checkpoint(&mut self, next: Position) -> Result<(), anyhow::Error> {
+        if let Some(failure) = self.failure.take() {
+            return Err(failure.into());
+        }
+        self.position = Some(next);
+        if self.unpositioned {
+            self.write(&WalLine::Position { position: next })?;
+            self.logged = Some(next);
+            self.unpositioned = false;
+        }
+        self.user.checkpoint(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Wal;
+    use crate::{
+        compute::Accounts,
+        data::{InputSource, TypedTransaction},
+        read::{read_transactions, resume_transactions, ReadOptions, TransactionUser},
+        testing::{TempPath, TRANSACTIONS_CSV},
+    };
+    use rust_decimal_macros::dec;
+    use std::io::Write;
+
+    #[test]
+    fn wal_replay() {
+        let path = TempPath::new("wal-replay.jsonl");
+        let mut expected = Accounts::new();
+        read_transactions(
+            TRANSACTIONS_CSV,
+            InputSource::User,
+            &mut expected,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        let mut wal = Wal::open(&path.0, Accounts::new(), 2).unwrap();
+        read_transactions(
+            TRANSACTIONS_CSV,
+            InputSource::User,
+            &mut wal,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(wal.finish().unwrap(), expected);
+        // the rejected withdrawal is logged, then cancelled
+        let log = std::fs::read_to_string(&path.0).unwrap();
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines.len(), 13);
+        assert_eq!(lines[7], r#"{"rejected":3}"#);
+        let wal = Wal::open(&path.0, Accounts::new(), 2).unwrap();
+        assert_eq!(wal.replayed(), 5);
+        assert_eq!(
+            wal.position().unwrap().byte(),
+            TRANSACTIONS_CSV.len() as u64
+        );
+        assert_eq!(wal.finish().unwrap(), expected);
+    }
+    #[test]
+    fn wal_resume() {
+        let path = TempPath::new("wal-resume.jsonl");
+        let mut expected = Accounts::new();
+        read_transactions(
+            TRANSACTIONS_CSV,
+            InputSource::User,
+            &mut expected,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        // stopped after the first three rows...
+        let end = TRANSACTIONS_CSV
+            .iter()
+            .enumerate()
+            .filter(|(_, &b)| b == b'\n')
+            .nth(3)
+            .unwrap()
+            .0;
+        let mut wal = Wal::open(&path.0, Accounts::new(), 1).unwrap();
+        read_transactions(
+            &TRANSACTIONS_CSV[..=end],
+            InputSource::User,
+            &mut wal,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        wal.finish().unwrap();
+        // ... and while logging the fourth one, which is dropped as it will be read again
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path.0)
+            .unwrap();
+        writeln!(
+            file,
+            r#"{{"Withdrawal":{{"client":2,"id":3,"amount":"5.0"}}}}"#
+        )
+        .unwrap();
+        drop(file);
+        let mut wal = Wal::open(&path.0, Accounts::new(), 1).unwrap();
+        let start = wal.position().unwrap();
+        assert_eq!(start.byte(), end as u64 + 1);
+        resume_transactions(
+            std::io::Cursor::new(TRANSACTIONS_CSV),
+            start,
+            InputSource::User,
+            &mut wal,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(wal.finish().unwrap(), expected);
+        let wal = Wal::open(&path.0, Accounts::new(), 1).unwrap();
+        assert_eq!(wal.finish().unwrap(), expected);
+    }
+    #[test]
+    fn wal_rejected() {
+        let path = TempPath::new("wal-rejected.jsonl");
+        let deposit = r#"{"Deposit":{"client":1,"id":1,"amount":"1.0000"}}"#;
+        let withdrawal = r#"{"Withdrawal":{"client":1,"id":2,"amount":"5.0"}}"#;
+        let mut wal = Wal::open(&path.0, Accounts::new(), 1).unwrap();
+        wal.use_tx(serde_json::from_str(deposit).unwrap()).unwrap();
+        let expected = wal.finish().unwrap();
+        // stopped after logging the withdrawal, before applying it
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path.0)
+            .unwrap();
+        writeln!(file, "{withdrawal}").unwrap();
+        drop(file);
+        let wal = Wal::open(&path.0, Accounts::new(), 1).unwrap();
+        assert_eq!(wal.finish().unwrap(), expected);
+        let log = std::fs::read_to_string(&path.0).unwrap();
+        assert_eq!(
+            log,
+            format!("{deposit}\n{withdrawal}\n{{\"rejected\":2}}\n")
+        );
+        // and it stays cancelled, even once it would be accepted
+        let mut wal = Wal::open(&path.0, Accounts::new(), 1).unwrap();
+        let deposit = r#"{"Deposit":{"client":1,"id":3,"amount":"9.0"}}"#;
+        wal.use_tx(serde_json::from_str(deposit).unwrap()).unwrap();
+        let accounts = wal.finish().unwrap();
+        let wal = Wal::open(&path.0, Accounts::new(), 1).unwrap();
+        assert_eq!(wal.finish().unwrap(), accounts);
+        assert_eq!(accounts.account(1).unwrap().total(), dec!(10));
+    }
+    #[test]
+    fn wal_torn_write() {
+        let path = TempPath::new("wal-torn.jsonl");
+        let mut wal = Wal::open(&path.0, Accounts::new(), 1).unwrap();
+        let deposit: TypedTransaction =
+            serde_json::from_str(r#"{"Deposit":{"client":1,"id":1,"amount":"1.0"}}"#).unwrap();
+        wal.use_tx(deposit).unwrap();
+        let expected = wal.finish().unwrap();
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path.0)
+            .unwrap();
+        file.write_all(br#"{"Deposit":{"client":1,"#).unwrap();
+        drop(file);
+        let wal = Wal::open(&path.0, Accounts::new(), 1).unwrap();
+        assert_eq!(wal.finish().unwrap(), expected);
+        let log = std::fs::read_to_string(&path.0).unwrap();
+        assert!(log.ends_with("}\n"));
+    }
+}
@@ -1,14 +1,439 @@
-use crate::compute::Accounts;
+use crate::{
+    compute::Accounts,
+    data::{Account, Error, Format},
+    ledger::Ledger,
+    read::RejectSink,
+    statement::Statement,
+    store::{AccountStore, TxStore},
+};
+use rust_decimal::Decimal;
+use std::{cmp::Reverse, io::Write};
+
+/// Order in which accounts are written; whatever the order, ties are ordered by client id.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AccountOrder {
+    #[default]
+    Client,
+    /// Largest total first
+    Total,
+    /// Locked accounts first
+    Locked,
+}
+
+fn sorted_accounts<S: TxStore, A: AccountStore>(
+    accounts: &Accounts<S, A>,
+    order: AccountOrder,
+) -> Result<Vec<Account>, Error> {
+    // accounts are already ordered by client id, and sorting is stable
+    let mut sorted = Vec::new();
+    accounts
+        .account_store()
+        .for_each(&mut |account| sorted.push(account.clone()))?;
+    match order {
+        AccountOrder::Client => {}
+        AccountOrder::Total => sorted.sort_by_key(|account| {
+            Reverse(Decimal::from(account.available) + Decimal::from(account.held))
+        }),
+        AccountOrder::Locked => sorted.sort_by_key(|account| !account.locked),
+    }
+    Ok(sorted)
+}
 
 /// Basic CSV exporter for `Accounts`
-pub(crate) fn write_accounts<W: std::io::Write>(
+pub fn write_accounts<W: std::io::Write, S: TxStore, A: AccountStore>(
     writer: W,
-    accounts: &Accounts,
+    accounts: &Accounts<S, A>,
+    order: AccountOrder,
 ) -> Result<(), anyhow::Error> {
     let mut wtr = csv::Writer::from_writer(writer);
-    for account in accounts.accounts.values() {
+    for account in sorted_accounts(accounts, order)? {
         wtr.serialize(account)?;
     }
     wtr.flush()?;
     Ok(())
 }
+
+/// JSON Lines exporter for `Accounts`, one object per account with the same fields as the
+/// CSV columns.
+pub fn write_accounts_json<W: std::io::Write, S: TxStore, A: AccountStore>(
+    mut writer: W,
+    accounts: &Accounts<S, A>,
+    order: AccountOrder,
+) -> Result<(), anyhow::Error> {
+    for account in sorted_accounts(accounts, order)? {
+        serde_json::to_writer(&mut writer, &account)?;
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Exporter for a `Ledger`, one row (or JSON object) per event
+pub fn write_ledger<W: std::io::Write>(
+    mut writer: W,
+    ledger: &Ledger,
+    format: Format,
+) -> Result<(), anyhow::Error> {
+    match format {
+        Format::Csv => {
+            let mut wtr = csv::Writer::from_writer(writer);
+            for event in ledger.events() {
+                wtr.serialize(event)?;
+            }
+            wtr.flush()?;
+        }
+        Format::JsonLines => {
+            for event in ledger.events() {
+                serde_json::to_writer(&mut writer, event)?;
+                writeln!(writer)?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Exporter for a `Statement`, one row (or JSON object) per balance or operation
+pub fn write_statement<W: std::io::Write>(
+    mut writer: W,
+    statement: &Statement,
+    format: Format,
+) -> Result<(), anyhow::Error> {
+    match format {
+        Format::Csv => {
+            let mut wtr = csv::Writer::from_writer(writer);
+            for row in statement.rows() {
+                wtr.serialize(row)?;
+            }
+            wtr.flush()?;
+        }
+        Format::JsonLines => {
+            for row in statement.rows() {
+                serde_json::to_writer(&mut writer, &row)?;
+                writeln!(writer)?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Columns of a transaction in a CSV rejects file, whatever the columns of the input
+const TRANSACTION_COLUMNS: [&str; 7] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "operator",
+    "reason",
+    "timestamp",
+];
+/// Columns written as numbers in a JSON Lines rejects file, so it can be read again
+const NUMBER_COLUMNS: [&str; 3] = ["client", "tx", "timestamp"];
+/// Columns added to the transaction ones when writing a rejected row
+const REJECT_COLUMNS: [&str; 4] = ["line", "error", "message", "content"];
+
+/// Exporter for rejected transactions: each row is written with its original fields,
+/// followed by its line number, error code (see `Error::code`), error message and, for
+/// rows which couldn't be read, their content as it was read.
+/// `read_transactions` ignores these extra columns, so a CSV rejects file can be fed
+/// again once corrected. As rows don't all have the same fields (e.g. JSON Lines objects
+/// without `amount`), CSV rejects always have every transaction column, in the same order.
+pub struct RejectWriter<W: Write>(RejectOutput<W>);
+
+enum RejectOutput<W: Write> {
+    Csv {
+        wtr: Box<csv::Writer<W>>,
+        headers_written: bool,
+    },
+    JsonLines(W),
+}
+
+impl<W: Write> RejectWriter<W> {
+    pub fn new(writer: W, format: Format) -> Self {
+        Self(match format {
+            Format::Csv => RejectOutput::Csv {
+                wtr: Box::new(csv::Writer::from_writer(writer)),
+                headers_written: false,
+            },
+            Format::JsonLines => RejectOutput::JsonLines(writer),
+        })
+    }
+    /// Same as `new`, to add to rejects written before (e.g. when resuming): the CSV header
+    /// isn't written again.
+    pub fn append(writer: W, format: Format) -> Self {
+        let mut rejects = Self::new(writer, format);
+        if let RejectOutput::Csv {
+            headers_written, ..
+        } = &mut rejects.0
+        {
+            *headers_written = true;
+        }
+        rejects
+    }
+    pub fn flush(&mut self) -> Result<(), anyhow::Error> {
+        match &mut self.0 {
+            RejectOutput::Csv { wtr, .. } => wtr.flush()?,
+            RejectOutput::JsonLines(writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+    /// Flushes and gives back the underlying writer
+    pub fn into_inner(self) -> Result<W, anyhow::Error> {
+        Ok(match self.0 {
+            RejectOutput::Csv { wtr, .. } => wtr.into_inner().map_err(|e| e.into_error())?,
+            RejectOutput::JsonLines(mut writer) => {
+                writer.flush()?;
+                writer
+            }
+        })
+    }
+}
+
+impl RejectWriter<std::io::BufWriter<std::fs::File>> {
+    /// Flushes the rejects and forces them to disk, giving back the length of the file
+    pub fn sync(&mut self) -> Result<u64, anyhow::Error> {
+        self.flush()?;
+        let file = match &self.0 {
+            RejectOutput::Csv { wtr, .. } => wtr.get_ref().get_ref(),
+            RejectOutput::JsonLines(writer) => writer.get_ref(),
+        };
+        file.sync_data()?;
+        Ok(file.metadata()?.len())
+    }
+}
+
+impl<W: Write> RejectWriter<W> {
+    fn write(
+        &mut self,
+        headers: &csv::StringRecord,
+        record: &csv::StringRecord,
+        line: u64,
+        error: &Error,
+        content: Option<&str>,
+    ) -> Result<(), anyhow::Error> {
+        // when feeding a rejects file again, don't repeat its reject columns
+        let fields = headers
+            .iter()
+            .zip(record.iter())
+            .filter(|(header, _)| !REJECT_COLUMNS.contains(header));
+        match &mut self.0 {
+            RejectOutput::Csv {
+                wtr,
+                headers_written,
+            } => {
+                if !*headers_written {
+                    wtr.write_record(TRANSACTION_COLUMNS.iter().chain(&REJECT_COLUMNS))?;
+                    *headers_written = true;
+                }
+                let field = |column| {
+                    let mut fields = fields.clone();
+                    fields
+                        .find(|&(header, _)| header == column)
+                        .map_or("", |(_, field)| field)
+                };
+                let line = line.to_string();
+                let message = error.to_string();
+                let extra = [
+                    line.as_str(),
+                    error.code(),
+                    message.as_str(),
+                    content.unwrap_or_default(),
+                ];
+                wtr.write_record(TRANSACTION_COLUMNS.map(field).into_iter().chain(extra))?;
+            }
+            RejectOutput::JsonLines(writer) => {
+                // empty fields are left out, and those which don't parse are kept as they are
+                let mut object: serde_json::Map<String, serde_json::Value> = fields
+                    .filter(|(_, field)| !field.is_empty())
+                    .map(|(header, field)| {
+                        let number = NUMBER_COLUMNS
+                            .contains(&header)
+                            .then(|| field.parse::<u64>().ok())
+                            .flatten();
+                        (
+                            header.into(),
+                            number.map_or_else(|| field.into(), Into::into),
+                        )
+                    })
+                    .collect();
+                object.insert("line".into(), line.into());
+                object.insert("error".into(), error.code().into());
+                object.insert("message".into(), error.to_string().into());
+                if let Some(content) = content {
+                    object.insert("content".into(), content.into());
+                }
+                serde_json::to_writer(&mut *writer, &object)?;
+                writeln!(writer)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> RejectSink for RejectWriter<W> {
+    fn reject(
+        &mut self,
+        headers: &csv::StringRecord,
+        record: &csv::StringRecord,
+        line: u64,
+        error: &Error,
+    ) -> Result<(), anyhow::Error> {
+        self.write(headers, record, line, error, None)
+    }
+    fn reject_malformed(
+        &mut self,
+        headers: &csv::StringRecord,
+        record: &csv::StringRecord,
+        line: u64,
+        content: &str,
+        error: &Error,
+    ) -> Result<(), anyhow::Error> {
+        self.write(headers, record, line, error, Some(content))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compute::Accounts,
+        data::{
+            amount, Account, Error, Format, InputSource, Transaction, TxType, TypedTransaction,
+        },
+        read::{
+            read_transactions, read_transactions_json, ReadOptions, RejectSink, TransactionUser,
+        },
+        write::{write_accounts, write_accounts_json, AccountOrder, RejectWriter},
+    };
+
+    #[test]
+    fn write_sorted() {
+        let mut accounts = Accounts::new();
+        for (client, available, locked) in [
+            (3, amount!(10), true),
+            (1, amount!(20), false),
+            (2, amount!(30), false),
+            (4, amount!(20), false),
+        ] {
+            accounts.accounts.insert(
+                client,
+                Account {
+                    client,
+                    available,
+                    locked,
+                    ..Account::default()
+                },
+            );
+        }
+        let clients = |order| {
+            let mut output = Vec::new();
+            write_accounts(&mut output, &accounts, order).unwrap();
+            String::from_utf8(output)
+                .unwrap()
+                .lines()
+                .skip(1)
+                .map(|line| line.split(',').next().unwrap().to_owned())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        assert_eq!(clients(AccountOrder::Client), "1,2,3,4");
+        assert_eq!(clients(AccountOrder::Total), "2,1,4,3");
+        assert_eq!(clients(AccountOrder::Locked), "3,1,2,4");
+    }
+    #[test]
+    fn write_json() {
+        let mut accounts = Accounts::new();
+        accounts.accounts.insert(
+            1,
+            Account {
+                client: 1,
+                available: amount!(1.5),
+                held: amount!(2),
+                ..Account::default()
+            },
+        );
+        let mut output = Vec::new();
+        write_accounts_json(&mut output, &accounts, AccountOrder::Client).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            r#"{"client":1,"available":"1.5000","held":"2.0000","total":"3.5000","locked":false}"#
+                .to_owned()
+                + "\n"
+        );
+    }
+    #[test]
+    fn write_rejects_appended() {
+        let headers = csv::StringRecord::from(vec!["type", "client", "tx"]);
+        let record = |id| csv::StringRecord::from(vec!["dispute", "1", id]);
+        let mut rejects = RejectWriter::new(Vec::new(), Format::Csv);
+        let error = Error::TransactionNotFound(1);
+        rejects.reject(&headers, &record("1"), 2, &error).unwrap();
+        let output = rejects.into_inner().unwrap();
+        let mut rejects = RejectWriter::append(output, Format::Csv);
+        let error = Error::TransactionNotFound(2);
+        rejects.reject(&headers, &record("2"), 5, &error).unwrap();
+        assert_eq!(
+            String::from_utf8(rejects.into_inner().unwrap()).unwrap(),
+            "\
+type,client,tx,amount,operator,reason,timestamp,line,error,message,content
+dispute,1,1,,,,,2,transaction_not_found,Transaction #1 not found,
+dispute,1,2,,,,,5,transaction_not_found,Transaction #2 not found,
+"
+        );
+    }
+    #[test]
+    fn write_rejects_json() {
+        let transactions_csv = b"\
+type,       client, tx, amount, timestamp
+withdrawal, 1,      1,  1.5,    1700000000
+dispute,    2,      7,         ,
+deposit,    x,      2,  1.0,
+";
+        let mut rejects = RejectWriter::new(Vec::new(), Format::JsonLines);
+        read_transactions(
+            &transactions_csv[..],
+            InputSource::User,
+            &mut Accounts::new(),
+            ReadOptions::default().with_rejects(&mut rejects),
+        )
+        .unwrap();
+        let output = rejects.into_inner().unwrap();
+        assert_eq!(
+            String::from_utf8(output.clone()).unwrap().lines().next(),
+            Some(
+                r#"{"amount":"1.5","client":1,"error":"insufficient_funds","line":2,"message":"Insufficient funds for operation (asked 1.5000 while 0.0000 available)","timestamp":1700000000,"tx":1,"type":"withdrawal"}"#
+            )
+        );
+        // the rejects can be fed again
+        #[derive(Default)]
+        struct TxStorage(Vec<TypedTransaction>);
+        impl TransactionUser for TxStorage {
+            fn use_tx(&mut self, tx: TypedTransaction) -> Result<(), Error> {
+                self.0.push(tx);
+                Ok(())
+            }
+        }
+        let mut storage = TxStorage::default();
+        let report = read_transactions_json(
+            &output[..],
+            InputSource::User,
+            &mut storage,
+            ReadOptions::default(),
+        )
+        .unwrap();
+        let withdrawal = Transaction::new(TxType::Withdrawal, 1, 1)
+            .with_amount(rust_decimal_macros::dec!(1.5))
+            .with_timestamp(1700000000);
+        let dispute = Transaction::new(TxType::Dispute, 2, 7);
+        assert_eq!(
+            storage.0,
+            [
+                TypedTransaction::try_from(withdrawal).unwrap(),
+                TypedTransaction::try_from(dispute).unwrap(),
+            ]
+        );
+        // the malformed row stays malformed
+        assert_eq!(report.errors().len(), 1);
+        assert_eq!(report.errors()[0].line(), 3);
+    }
+}
@@ -0,0 +1,122 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Temporary file, removed when dropped
+struct TempPath(PathBuf);
+
+impl TempPath {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("cli-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Self(path)
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn payments(args: &[&std::ffi::OsStr]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_payments"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn wal_rerun() {
+    let input = TempPath::new("wal-rerun.csv");
+    let wal = TempPath::new("wal-rerun.jsonl");
+    std::fs::write(
+        &input.0,
+        "type,client,tx,amount\n\
+         deposit,1,1,1.0\n\
+         withdrawal,1,2,5.0\n\
+         deposit,1,3,9.0\n",
+    )
+    .unwrap();
+    let rejects = TempPath::new("wal-rerun-rejects.csv");
+    let args = [
+        "--wal".as_ref(),
+        wal.0.as_os_str(),
+        "--rejects".as_ref(),
+        rejects.0.as_os_str(),
+        input.0.as_os_str(),
+    ];
+    let first = payments(&args);
+    assert!(first.contains("1,10"), "{first}");
+    let rejected = std::fs::read_to_string(&rejects.0).unwrap();
+    assert_eq!(rejected.lines().count(), 2);
+    // the log already holds the whole input, which isn't read again
+    assert_eq!(payments(&args), first);
+    assert_eq!(std::fs::read_to_string(&rejects.0).unwrap(), rejected);
+}
+#[test]
+fn wal_rerun_abort() {
+    let input = TempPath::new("wal-abort.csv");
+    let wal = TempPath::new("wal-abort.jsonl");
+    std::fs::write(
+        &input.0,
+        "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,9.0\n",
+    )
+    .unwrap();
+    let args = [
+        "--on-error".as_ref(),
+        "abort".as_ref(),
+        "--wal".as_ref(),
+        wal.0.as_os_str(),
+        input.0.as_os_str(),
+    ];
+    let first = payments(&args);
+    assert!(first.contains("1,10"), "{first}");
+    assert_eq!(payments(&args), first);
+}
+#[test]
+fn snapshot_resume_rejects() {
+    let input = TempPath::new("resume.csv");
+    let snapshot = TempPath::new("resume-snapshot.json");
+    let rejects = TempPath::new("resume-rejects.csv");
+    std::fs::write(
+        &input.0,
+        "type,client,tx,amount\n\
+         deposit,1,1,1.0\n\
+         withdrawal,1,2,5.0\n\
+         dispute,1,7,\n",
+    )
+    .unwrap();
+    let first = payments(&[
+        "--snapshot".as_ref(),
+        snapshot.0.as_os_str(),
+        "--snapshot-every".as_ref(),
+        "2".as_ref(),
+        "--rejects".as_ref(),
+        rejects.0.as_os_str(),
+        input.0.as_os_str(),
+    ]);
+    let rejected = std::fs::read_to_string(&rejects.0).unwrap();
+    assert_eq!(rejected.lines().count(), 3);
+    // a reject written after the last snapshot, before crashing
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&rejects.0)
+        .unwrap();
+    std::io::Write::write_all(&mut file, b"deposit,1,9,1.0,,,,5,duplicate,Duplicate,\n").unwrap();
+    drop(file);
+    let resumed = payments(&[
+        "--resume-from".as_ref(),
+        snapshot.0.as_os_str(),
+        "--rejects".as_ref(),
+        rejects.0.as_os_str(),
+        input.0.as_os_str(),
+    ]);
+    assert_eq!(resumed, first);
+    assert_eq!(std::fs::read_to_string(&rejects.0).unwrap(), rejected);
+}